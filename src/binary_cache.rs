@@ -1,5 +1,6 @@
+use crate::env::check_online;
 use crate::github::get_latest_release_version;
-use crate::utils::create_spinner_bar;
+use crate::utils::{create_spinner_bar, println};
 use anyhow::Result;
 use async_trait::async_trait;
 use console::style;
@@ -28,12 +29,16 @@ pub trait Binary: Send + Sync {
             } else {
                 if need_latest {
                     let latest = get_latest_release_version(self.repo()).await?;
-                    println!(
-                        "Latest {} is {}, but {} is pinned to {} in mops.toml",
-                        self.name(),
-                        latest,
-                        self.name(),
-                        exp_ver
+                    println(
+                        None,
+                        "stdout",
+                        &format!(
+                            "Latest {} is {}, but {} is pinned to {} in mops.toml",
+                            self.name(),
+                            latest,
+                            self.name(),
+                            exp_ver
+                        ),
                     );
                     return Ok(());
                 }
@@ -52,7 +57,11 @@ pub trait Binary: Send + Sync {
         if let Some(ver) = need_ver {
             self.download_binary(ver).await?;
         } else if need_latest {
-            println!("{} is already up-to-date", self.name());
+            println(
+                None,
+                "stdout",
+                &format!("{} is already up-to-date", self.name()),
+            );
         }
         Ok(())
     }
@@ -126,6 +135,42 @@ impl Binary for Fmt {
         Ok(())
     }
 }
+pub struct IcWasm {
+    pub binary_path: PathBuf,
+    pub expect_version: Option<String>,
+}
+#[async_trait]
+impl Binary for IcWasm {
+    fn name(&self) -> &str {
+        "ic-wasm"
+    }
+    fn repo(&self) -> &str {
+        "dfinity/ic-wasm"
+    }
+    fn get_path(&self) -> PathBuf {
+        self.binary_path.join(self.name())
+    }
+    fn get_expect_version(&self) -> &Option<String> {
+        &self.expect_version
+    }
+    fn get_version(&self) -> Result<String> {
+        get_binary_version(self, 1)
+    }
+    async fn download_binary(&self, ver: String) -> Result<()> {
+        let platform = if cfg!(target_os = "macos") {
+            "macos"
+        } else if cfg!(target_os = "linux") {
+            "linux64"
+        } else {
+            anyhow::bail!("Unsupported platform");
+        };
+        let url = format!(
+            "https://github.com/dfinity/ic-wasm/releases/download/{ver}/ic-wasm-{platform}.tar.gz"
+        );
+        download_release(self, &url, &ver).await?;
+        Ok(())
+    }
+}
 
 fn get_binary_version(bin: &dyn Binary, pos: usize) -> Result<String> {
     let mut cmd = bin.get_cmd();
@@ -140,6 +185,7 @@ fn get_binary_version(bin: &dyn Binary, pos: usize) -> Result<String> {
 async fn download_release<T: Binary>(bin: &T, url: &str, ver: &str) -> Result<()> {
     use std::io::Write;
     let name = format!("{} {}", bin.name(), ver);
+    check_online(&format!("download {name}"))?;
     let bar = create_spinner_bar(format!("Downloading {name}"));
     let base_path = bin.get_path().parent().unwrap().to_path_buf();
     let gz_file = base_path.join(format!("{}-{}.tar.gz", bin.name(), ver));
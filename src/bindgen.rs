@@ -0,0 +1,193 @@
+use crate::env::Env;
+use crate::utils::println;
+use anyhow::{anyhow, Result};
+use candid_parser::bindings::{javascript, rust, typescript};
+use candid_parser::configs::Configs;
+use candid_parser::utils::CandidSource;
+use console::style;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use toml_edit::{DocumentMut, ImDocument};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Language {
+    Rust,
+    TypeScript,
+    JavaScript,
+}
+impl std::fmt::Display for Language {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            Language::Rust => "Rust",
+            Language::TypeScript => "TypeScript",
+            Language::JavaScript => "JavaScript",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A [bindings.<lang>] section in mops.toml
+pub struct Binding {
+    lang: Language,
+    /// Relative to the project root. `{name}` is replaced by the target name.
+    output: String,
+    /// Variables passed to the handlebars template of the Rust binding, e.g., target, template, canister_id
+    external: BTreeMap<String, String>,
+    /// Type configs of the Rust binding in the candid config format, e.g., attributes and use_type
+    bindgen: String,
+}
+
+/// Read the [bindings] section of mops.toml
+pub fn get_bindings(env: &Env) -> Result<Vec<Binding>> {
+    let path = env.get_mops_toml_path();
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let str = fs::read_to_string(path)?;
+    let doc = str.parse::<ImDocument<_>>()?;
+    let Some(bindings) = doc.get("bindings") else {
+        return Ok(Vec::new());
+    };
+    let bindings = bindings
+        .as_table()
+        .ok_or_else(|| anyhow!("bindings should be a table [bindings]"))?;
+    let mut res = Vec::new();
+    for (lang, table) in bindings.iter() {
+        let lang = match lang {
+            "rust" => Language::Rust,
+            "typescript" => Language::TypeScript,
+            "javascript" => Language::JavaScript,
+            _ => {
+                return Err(anyhow!(
+                "Unknown language {lang} in [bindings]. Expected rust, typescript or javascript."
+            ))
+            }
+        };
+        let section = format!("[bindings.{}]", lang.to_string().to_lowercase());
+        let table = table
+            .as_table()
+            .ok_or_else(|| anyhow!("{section} should be a table"))?;
+        let output = table
+            .get("output")
+            .and_then(|o| o.as_str())
+            .ok_or_else(|| anyhow!("output is required in {section}"))?
+            .to_string();
+        let mut external = BTreeMap::new();
+        let mut bindgen = String::new();
+        for (key, item) in table.iter() {
+            match key {
+                "output" => (),
+                "bindgen" if lang == Language::Rust => {
+                    let table = item
+                        .as_table()
+                        .ok_or_else(|| anyhow!("bindgen should be a table in {section}"))?;
+                    let mut doc = DocumentMut::new();
+                    *doc.as_table_mut() = table.clone();
+                    bindgen = doc.to_string();
+                }
+                _ if lang == Language::Rust => {
+                    let value = item
+                        .as_str()
+                        .ok_or_else(|| anyhow!("{key} should be a string in {section}"))?;
+                    let value = if key == "template" {
+                        let template = env.project_root.join(value);
+                        if !template.exists() {
+                            return Err(anyhow!("Cannot find template {}", template.display()));
+                        }
+                        template.to_string_lossy().to_string()
+                    } else {
+                        value.to_string()
+                    };
+                    external.insert(key.to_string(), value);
+                }
+                _ => return Err(anyhow!("{key} is not supported in {section}")),
+            }
+        }
+        res.push(Binding {
+            lang,
+            output,
+            external,
+            bindgen,
+        });
+    }
+    Ok(res)
+}
+
+/// Generate the bindings for each (target name, candid file)
+pub fn generate(env: &Env, bindings: &[Binding], targets: &[(String, PathBuf)]) -> Result<()> {
+    if targets.len() > 1 {
+        if let Some(b) = bindings.iter().find(|b| !b.output.contains("{name}")) {
+            return Err(anyhow!(
+                "Multiple targets write {} bindings to {}. Use {{name}} in the output path.",
+                b.lang,
+                b.output
+            ));
+        }
+    }
+    for (name, did) in targets {
+        if !did.exists() {
+            return Err(anyhow!(
+                "Cannot find {} to generate bindings. Build the target with --idl.",
+                did.display()
+            ));
+        }
+        let (type_env, actor) = CandidSource::File(did).load()?;
+        for b in bindings {
+            let res = match b.lang {
+                Language::Rust => {
+                    let configs = b.bindgen.parse::<Configs>()?;
+                    let config = rust::Config::new(configs);
+                    let mut external = rust::ExternalConfig::default();
+                    external.0.insert("target".to_string(), "agent".to_string());
+                    external.0.insert("service_name".to_string(), name.clone());
+                    if b.external.contains_key("template") {
+                        external
+                            .0
+                            .insert("target".to_string(), "custom".to_string());
+                    }
+                    external.0.extend(b.external.clone());
+                    let (res, unused) = rust::compile(&config, &type_env, &actor, external);
+                    for path in unused {
+                        println(
+                            None,
+                            "stderr",
+                            &format!(
+                                "{:>12} Unused config {path} in [bindings.rust.bindgen]",
+                                style("[Warning]").yellow().bold()
+                            ),
+                        );
+                    }
+                    res
+                }
+                Language::TypeScript => typescript::compile(&type_env, &actor),
+                Language::JavaScript => javascript::compile(&type_env, &actor),
+            };
+            let output = env.project_root.join(b.output.replace("{name}", name));
+            fs::create_dir_all(output.parent().unwrap())?;
+            fs::write(&output, res)?;
+            let display = output.strip_prefix(&env.project_root).unwrap_or(&output);
+            println(
+                None,
+                "stdout",
+                &format!(
+                    "{:>12} {} bindings for {name} at {}",
+                    style("Generated").green().bold(),
+                    b.lang,
+                    display.display()
+                ),
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Generate the bindings from target/<name>/<name>.did without building
+pub fn bindgen(env: &Env, args: crate::BindgenArg) -> Result<()> {
+    let bindings = get_bindings(env)?;
+    if bindings.is_empty() {
+        return Err(anyhow!("Cannot find [bindings] in mops.toml"));
+    }
+    let targets = env.get_built_files(&args.name, "did")?;
+    generate(env, &bindings, &targets)
+}
@@ -1,112 +1,629 @@
+use crate::diagnostics::{Diagnostics, Severity};
 use crate::env::Env;
-use crate::toml::{download_packages_from_lock, generate_moc_args, update_mops_toml};
-use crate::utils::{create_spinner_bar, exec};
+use crate::toml::{
+    download_packages_from_lock, generate_moc_args, get_lock_hash, update_mops_toml,
+};
+use crate::utils::{create_spinner_bar, emit, exec, println, to_hex, Event};
 use anyhow::{anyhow, Context, Result};
 use candid::Principal;
 use console::style;
+use futures::StreamExt;
 use ic_agent::Agent;
-use indicatif::HumanDuration;
-use std::collections::BTreeSet;
+use indicatif::{HumanDuration, ProgressBar};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
 use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
 use std::time::Instant;
+use toml_edit::ImDocument;
+
+/// A Motoko program that compiles to target/<name>/<name>.wasm
+pub struct Target {
+    pub name: Option<String>,
+    pub main: PathBuf,
+    /// moc flags, except for -o and --package
+    pub args: Vec<String>,
+}
+
+impl Target {
+    fn display_main(&self, env: &Env) -> String {
+        let main = self
+            .main
+            .strip_prefix(&env.project_root)
+            .unwrap_or(&self.main);
+        main.display().to_string()
+    }
+}
+
+fn default_args() -> Vec<String> {
+    ["--release", "--idl", "--stable-types"]
+        .into_iter()
+        .chain(["--public-metadata", "candid:service"])
+        .map(|s| s.to_string())
+        .collect()
+}
 
 pub async fn build(agent: &Agent, env: &Env, args: crate::BuildArg) -> Result<()> {
-    let main_file = if let Some(file) = args.main {
-        file
+    let bins = get_bin_targets(env)?;
+    let selected = args
+        .main
+        .as_ref()
+        .and_then(|m| m.to_str())
+        .and_then(|m| bins.iter().position(|t| t.name.as_deref() == Some(m)));
+    let uses_bins = selected.is_some() || (args.main.is_none() && !bins.is_empty());
+    if uses_bins && args.name.is_some() {
+        // Each [[bin]] target has its own name
+        return Err(anyhow!("--name cannot be used with [[bin]] targets"));
+    }
+    let targets = if args.workspace {
+        get_workspace_members(env)?
+    } else if let Some(idx) = selected {
+        bins.into_iter().skip(idx).take(1).collect()
+    } else if args.main.is_none() && !bins.is_empty() {
+        bins
     } else {
-        crate::env::guess_main_file()?
+        let main = if let Some(file) = args.main {
+            file
+        } else {
+            crate::env::guess_main_file(Path::new(""))?
+        };
+        vec![Target {
+            name: args.name,
+            main,
+            args: default_args(),
+        }]
     };
+    let has_output = args.extra_args.contains(&"-o".to_string());
+    if has_output && targets.len() > 1 {
+        return Err(anyhow!("Cannot use -o when building multiple targets"));
+    }
+    if args.check_candid.is_some() && (has_output || targets.len() > 1) {
+        return Err(anyhow!(
+            "--check-candid can only be used when building a single target without -o"
+        ));
+    }
+    let bindings = crate::bindgen::get_bindings(env)?;
+    let pipeline = crate::wasm::Pipeline::from_mops_toml(env)?;
+    if pipeline.as_ref().is_some_and(|p| p.needs_ic_wasm()) {
+        let ic_wasm = &env.binary["ic-wasm"];
+        if crate::env::is_offline() {
+            ic_wasm.get_version().map_err(|_| {
+                anyhow!(
+                    "ic-wasm is not found in {}. Run without --offline to download it.",
+                    env.get_binary_path().display()
+                )
+            })?;
+        } else {
+            ic_wasm.update_binary(false).await?;
+        }
+    }
+    let pipeline_config = pipeline.as_ref().map_or("", |p| p.config.as_str());
     let start = Instant::now();
-    if !args.lock {
-        let imports = get_imports(&main_file, env, args.print_source_on_error)?;
-        update_mops_toml(agent, env, imports).await?;
-        download_packages_from_lock(agent, env).await?;
+    // Imports are also needed for the build fingerprint
+    let mains: Vec<_> = targets.iter().map(|t| t.main.as_path()).collect();
+    let imports = get_imports(&mains, env, args.print_source_on_error).await?;
+    if !crate::env::is_offline() && !args.lock {
+        let all = imports.iter().flatten().cloned().collect();
+        update_mops_toml(agent, env, all).await?;
     }
+    // With --offline or --lock, only use mops.lock. Either way, the checksums of the cached packages are verified.
+    download_packages_from_lock(agent, env).await?;
     let lock_time = start.elapsed();
     let pkgs = generate_moc_args(env)?;
-    let msg = format!("{:>12} {}", style("Compiling").cyan(), main_file.display());
+    let lock_hash = get_lock_hash(env)?;
+    let moc_version = env.binary["moc"].get_version()?;
+    let msg = match targets.as_slice() {
+        [target] => format!(
+            "{:>12} {}",
+            style("Compiling").cyan(),
+            target.display_main(env)
+        ),
+        _ => format!(
+            "{:>12} {} targets",
+            style("Compiling").cyan(),
+            targets.len()
+        ),
+    };
     let bar = create_spinner_bar(msg);
-    let mut moc = env.binary["moc"].get_cmd();
-    moc.arg(&main_file).args(pkgs);
-    if !args.extra_args.contains(&"-o".to_string()) {
-        let output = env.get_target_build_path(&args.name, &main_file);
-        std::fs::create_dir_all(output.parent().unwrap())
-            .context("Failed to create output directory.")?;
-        moc.arg("-o").arg(output);
-    }
-    if args.print_source_on_error {
-        moc.arg("--print-source-on-error");
+    let mut handles = Vec::new();
+    let mut jobs = Vec::new();
+    // Outputs of the targets that are compiled or fresh
+    let mut built = Vec::new();
+    let mut failed = Vec::new();
+    for (target, imports) in targets.iter().zip(&imports) {
+        let mut moc = env.binary["moc"].get_cmd();
+        moc.arg(&target.main).args(&pkgs);
+        let mut output = None;
+        if !has_output {
+            let path = env.get_target_build_path(&target.name, &target.main);
+            std::fs::create_dir_all(path.parent().unwrap())
+                .context("Failed to create output directory.")?;
+            moc.arg("-o").arg(&path);
+            output = Some(path);
+        }
+        if !args.extra_args.is_empty() {
+            moc.args(&args.extra_args);
+        } else {
+            moc.args(&target.args);
+        }
+        // Skip moc when the output is built with the same inputs
+        let fingerprint = match &output {
+            Some(output) => {
+                let path = output.parent().unwrap().join(".fingerprint");
+                let hash = fingerprint(imports, &moc, &lock_hash, &moc_version, pipeline_config)?;
+                // The fingerprint file stores the hash, followed by the compiler messages of that build
+                let old = std::fs::read_to_string(&path).unwrap_or_default();
+                let fresh = old.split_once('\n').filter(|(old, _)| *old == hash);
+                if let (true, Some((_, stderr))) = (output.exists(), fresh) {
+                    // Replay the warnings, so that they are not lost and --deny-warnings still applies
+                    let diagnostics = Diagnostics::parse(stderr);
+                    print_diagnostics(&diagnostics, &bar);
+                    let warnings = diagnostics.count(Severity::Warning);
+                    if args.deny_warnings && warnings > 0 {
+                        if targets.len() == 1 {
+                            return Err(anyhow!(
+                                "Could not compile {} due to {warnings} warning(s) with --deny-warnings",
+                                target.display_main(env)
+                            ));
+                        }
+                        failed.push(target.display_main(env));
+                        continue;
+                    }
+                    emit(Event::Fresh {
+                        main: &target.main,
+                        output,
+                    });
+                    let mut msg = format!(
+                        "{:>12} {}",
+                        style("Fresh").green().bold(),
+                        target.display_main(env)
+                    );
+                    if warnings > 0 {
+                        msg.push_str(&format!(" with {warnings} warning(s)"));
+                    }
+                    println(Some(&bar), "stdout", &msg);
+                    built.push(output.clone());
+                    continue;
+                }
+                Some((path, hash))
+            }
+            None => None,
+        };
+        emit(Event::CompileStarted { main: &target.main });
+        jobs.push((target, output, fingerprint));
+        // moc runs in parallel when there are multiple canisters
+        handles.push(tokio::task::spawn_blocking(move || {
+            let start = Instant::now();
+            let res = moc
+                .output()
+                .with_context(|| format!("Error executing {:#?}", moc));
+            (res, start.elapsed())
+        }));
     }
-    if !args.extra_args.is_empty() {
-        for arg in args.extra_args {
-            moc.arg(arg);
+    let results = futures::future::join_all(handles).await;
+    for ((target, output, fingerprint), res) in jobs.into_iter().zip(results) {
+        let (res, elapsed) = res?;
+        let res = res.and_then(|out| {
+            let stderr = String::from_utf8_lossy(&out.stderr).to_string();
+            report(out, &bar).map(|d| (d, stderr))
+        });
+        let warnings = res.as_ref().map_or(0, |(d, _)| d.count(Severity::Warning));
+        let success = res.is_ok() && !(args.deny_warnings && warnings > 0);
+        let elapsed = if targets.len() == 1 {
+            start.elapsed()
+        } else {
+            elapsed
+        };
+        emit(Event::CompileFinished {
+            main: &target.main,
+            output: output.as_deref(),
+            success,
+            duration_ms: elapsed.as_millis(),
+        });
+        match res {
+            Err(e) if targets.len() == 1 => return Err(e),
+            Err(e) => {
+                println(Some(&bar), "stderr", &format!("{e:#}"));
+                failed.push(target.display_main(env));
+                continue;
+            }
+            Ok(_) if !success => {
+                if targets.len() == 1 {
+                    return Err(anyhow!(
+                        "Could not compile {} due to {warnings} warning(s) with --deny-warnings",
+                        target.display_main(env)
+                    ));
+                }
+                failed.push(target.display_main(env));
+                continue;
+            }
+            Ok(_) => (),
         }
-    } else {
-        moc.arg("--release")
-            .arg("--idl")
-            .arg("--stable-types")
-            .arg("--public-metadata")
-            .arg("candid:service");
+        if let (Some(pipeline), Some(output)) = (&pipeline, &output) {
+            pipeline.run(env, output, &bar)?;
+        }
+        if let (Some((path, hash)), Ok((_, stderr))) = (fingerprint, &res) {
+            std::fs::write(path, format!("{hash}\n{stderr}"))?;
+        }
+        if let Some(output) = output {
+            built.push(output);
+        }
+        let mut msg = format!(
+            "{:>12} {} in {}",
+            style("Compiled").green().bold(),
+            target.display_main(env),
+            HumanDuration(elapsed)
+        );
+        if warnings > 0 {
+            msg.push_str(&format!(" with {warnings} warning(s)"));
+        }
+        if targets.len() == 1 && !args.lock && !crate::env::is_offline() {
+            msg.push_str(&format!(
+                " ({} to analyze dependencies)",
+                HumanDuration(lock_time)
+            ));
+        }
+        println(Some(&bar), "stdout", &msg);
     }
-    exec(moc, false, Some(&bar))?;
     bar.finish_and_clear();
-    let mut msg = format!(
-        "{:>12} {} in {}",
-        style("Compiled").green().bold(),
-        main_file.display(),
-        HumanDuration(start.elapsed())
-    );
-    if !args.lock {
-        msg.push_str(&format!(
-            " ({} to analyze dependencies)",
-            HumanDuration(lock_time)
-        ));
+    if !failed.is_empty() {
+        return Err(anyhow!("Could not compile:\n{}", failed.join("\n")));
+    }
+    if !bindings.is_empty() {
+        let targets: Vec<_> = built
+            .iter()
+            .map(|output| {
+                let name = output.file_stem().unwrap().to_string_lossy().to_string();
+                (name, output.with_extension("did"))
+            })
+            .collect();
+        crate::bindgen::generate(env, &bindings, &targets)?;
+    }
+    if let Some(against) = &args.check_candid {
+        for output in &built {
+            let name = output.file_stem().unwrap().to_string_lossy();
+            let did = output.with_extension("did");
+            crate::upgrade::check_candid_file(agent, env, &name, &did, against).await?;
+        }
+    }
+    if targets.len() > 1 {
+        let mut msg = format!(
+            "{:>12} {} targets in {}",
+            style("Finished").green().bold(),
+            targets.len(),
+            HumanDuration(start.elapsed())
+        );
+        if !args.lock && !crate::env::is_offline() {
+            msg.push_str(&format!(
+                " ({} to analyze dependencies)",
+                HumanDuration(lock_time)
+            ));
+        }
+        println(None, "stdout", &msg);
+    }
+    Ok(())
+}
+/// Hash of everything that affects the build output: local source files, resolved packages, moc version and flags, and the [wasm] pipeline
+fn fingerprint(
+    imports: &BTreeSet<MotokoImport>,
+    moc: &Command,
+    lock_hash: &str,
+    moc_version: &str,
+    pipeline: &str,
+) -> Result<String> {
+    let mut hasher = Sha256::new();
+    for import in imports {
+        if let MotokoImport::Local(path) = import {
+            hasher.update(path.to_string_lossy().as_bytes());
+            hasher.update([0]);
+            hasher.update(std::fs::read(path)?);
+            hasher.update([0]);
+        }
+    }
+    for arg in moc.get_args() {
+        hasher.update(arg.as_encoded_bytes());
+        hasher.update([0]);
+    }
+    hasher.update(lock_hash.as_bytes());
+    hasher.update([0]);
+    hasher.update(moc_version.as_bytes());
+    hasher.update([0]);
+    hasher.update(pipeline.as_bytes());
+    Ok(to_hex(hasher))
+}
+/// Display the diagnostics of a moc run
+fn report(output: Output, bar: &ProgressBar) -> Result<Diagnostics> {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let diagnostics = Diagnostics::parse(&stderr);
+    print_diagnostics(&diagnostics, bar);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if !stdout.is_empty() {
+        println(Some(bar), "stdout", &stdout);
+    }
+    if !output.status.success() {
+        let errors = diagnostics.count(Severity::Error);
+        return Err(if errors > 0 {
+            anyhow!("Could not compile due to {errors} error(s)")
+        } else {
+            anyhow!("Exit with code {}", output.status)
+        });
+    }
+    Ok(diagnostics)
+}
+fn print_diagnostics(diagnostics: &Diagnostics, bar: &ProgressBar) {
+    for diag in &diagnostics.items {
+        emit(Event::CompilerMessage { diagnostic: diag });
+        println(Some(bar), "stderr", &diag.render());
+    }
+    if !diagnostics.other.is_empty() {
+        println(Some(bar), "stderr", diagnostics.other.trim_end());
+    }
+}
+/// Members in the [workspace] section of mops.toml. A member is either a directory, or a table with path, main and name.
+fn get_workspace_members(env: &Env) -> Result<Vec<Target>> {
+    let str = std::fs::read_to_string(env.get_mops_toml_path())?;
+    let doc = str.parse::<ImDocument<_>>()?;
+    let members = doc
+        .get("workspace")
+        .and_then(|w| w.get("members"))
+        .and_then(|m| m.as_array())
+        .ok_or_else(|| anyhow!("Cannot find [workspace] members in mops.toml"))?;
+    let mut res = Vec::new();
+    for member in members.iter() {
+        let (path, main, name) = if let Some(path) = member.as_str() {
+            (path, None, None)
+        } else if let Some(table) = member.as_inline_table() {
+            let get = |field| table.get(field).and_then(|v| v.as_str());
+            let path = get("path")
+                .ok_or_else(|| anyhow!("path is required in workspace member {member}"))?;
+            (path, get("main"), get("name"))
+        } else {
+            return Err(anyhow!("invalid workspace member {member}"));
+        };
+        let dir = env.project_root.join(path);
+        let main = match main {
+            Some(main) => dir.join(main),
+            None => crate::env::guess_main_file(&dir)?,
+        };
+        res.push(Target {
+            name: name.map(|n| n.to_string()),
+            main,
+            args: default_args(),
+        });
+    }
+    check_unique_outputs(env, &res)?;
+    Ok(res)
+}
+/// Build targets declared as [[bin]] in mops.toml
+fn get_bin_targets(env: &Env) -> Result<Vec<Target>> {
+    let path = env.get_mops_toml_path();
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let str = std::fs::read_to_string(path)?;
+    let doc = str.parse::<ImDocument<_>>()?;
+    let Some(bins) = doc.get("bin") else {
+        return Ok(Vec::new());
+    };
+    let bins = bins
+        .as_array_of_tables()
+        .ok_or_else(|| anyhow!("bin should be an array of tables [[bin]]"))?;
+    let mut res = Vec::new();
+    for bin in bins.iter() {
+        let get = |field| bin.get(field).and_then(|v| v.as_str());
+        let get_bool = |field, default| {
+            bin.get(field)
+                .map(|v| {
+                    v.as_bool()
+                        .ok_or_else(|| anyhow!("{field} should be a boolean in [[bin]]"))
+                })
+                .unwrap_or(Ok(default))
+        };
+        let name = get("name").ok_or_else(|| anyhow!("name is required in [[bin]]\n{bin}"))?;
+        let main = get("main").ok_or_else(|| anyhow!("main is required in [[bin]]\n{bin}"))?;
+        let mut args = match bin.get("args") {
+            Some(args) => args
+                .as_array()
+                .and_then(|a| {
+                    a.iter()
+                        .map(|v| v.as_str().map(|s| s.to_string()))
+                        .collect()
+                })
+                .ok_or_else(|| anyhow!("args should be an array of strings in [[bin]]"))?,
+            None => vec!["--release".to_string()],
+        };
+        if get_bool("idl", true)? {
+            args.extend(["--idl", "--public-metadata", "candid:service"].map(|s| s.to_string()));
+        }
+        if get_bool("stable_types", true)? {
+            args.push("--stable-types".to_string());
+        }
+        res.push(Target {
+            name: Some(name.to_string()),
+            main: env.project_root.join(main),
+            args,
+        });
+    }
+    check_unique_outputs(env, &res)?;
+    Ok(res)
+}
+fn check_unique_outputs(env: &Env, targets: &[Target]) -> Result<()> {
+    let mut names = BTreeSet::new();
+    for target in targets {
+        let output = env.get_target_build_path(&target.name, &target.main);
+        if !names.insert(output.clone()) {
+            return Err(anyhow!(
+                "Multiple targets are built into {}. Use name to distinguish them.",
+                output.display()
+            ));
+        }
     }
-    println!("{msg}");
     Ok(())
 }
 
-#[derive(Debug, PartialOrd, Ord, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialOrd, Ord, PartialEq, Eq)]
 pub enum MotokoImport {
     Canister(String),
     Ic(Principal),
     Lib(String),
     Local(PathBuf),
 }
-fn get_imports(main_path: &Path, env: &Env, display_src: bool) -> Result<BTreeSet<MotokoImport>> {
-    fn get_imports_recursive(
-        env: &Env,
-        file: &Path,
-        display_src: bool,
-        result: &mut BTreeSet<MotokoImport>,
-    ) -> Result<()> {
-        if result.contains(&MotokoImport::Local(file.to_path_buf())) {
-            return Ok(());
-        }
-        result.insert(MotokoImport::Local(file.to_path_buf()));
-        let mut command = env.binary["moc"].get_cmd();
-        command.arg("--print-deps").arg(file);
-        if display_src {
-            command.arg("--print-source-on-error");
-        }
-        let output = exec(command, true, None)?;
-        for line in output.lines() {
-            let import = MotokoImport::try_from(line).context("Failed to parse import.")?;
-            match import {
-                MotokoImport::Local(path) => {
-                    get_imports_recursive(env, path.as_path(), display_src, result)?;
+/// Local imports of each file reported by `moc --print-deps`, reused across builds
+#[derive(Serialize, Deserialize, Default)]
+struct ImportCache {
+    moc_version: String,
+    files: BTreeMap<PathBuf, CachedImports>,
+}
+#[derive(Serialize, Deserialize)]
+struct CachedImports {
+    mtime: u64,
+    hash: String,
+    deps: String,
+}
+impl ImportCache {
+    fn load(path: &Path, moc_version: &str) -> Self {
+        match std::fs::read_to_string(path)
+            .ok()
+            .and_then(|str| serde_json::from_str::<ImportCache>(&str).ok())
+        {
+            Some(cache) if cache.moc_version == moc_version => cache,
+            _ => ImportCache {
+                moc_version: moc_version.to_string(),
+                files: BTreeMap::new(),
+            },
+        }
+    }
+    /// Returns the cached deps if the file is unchanged. The hash is only computed when mtime differs.
+    fn get(&mut self, file: &Path) -> Result<Option<String>> {
+        let Some(entry) = self.files.get_mut(file) else {
+            return Ok(None);
+        };
+        let mtime = get_mtime(file)?;
+        if entry.mtime == mtime {
+            return Ok(Some(entry.deps.clone()));
+        }
+        if entry.hash == hash_file(file)? {
+            entry.mtime = mtime;
+            return Ok(Some(entry.deps.clone()));
+        }
+        Ok(None)
+    }
+    fn insert(&mut self, file: &Path, deps: String) -> Result<()> {
+        let entry = CachedImports {
+            mtime: get_mtime(file)?,
+            hash: hash_file(file)?,
+            deps,
+        };
+        self.files.insert(file.to_path_buf(), entry);
+        Ok(())
+    }
+}
+fn get_mtime(file: &Path) -> Result<u64> {
+    let mtime = std::fs::metadata(file)?
+        .modified()?
+        .duration_since(std::time::UNIX_EPOCH)?;
+    Ok(mtime.as_nanos() as u64)
+}
+fn hash_file(file: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(std::fs::read(file)?);
+    Ok(to_hex(hasher))
+}
+/// Returns the transitive imports of each main file. Files are analyzed concurrently,
+/// and the results are cached in `target/imports.json`.
+pub async fn get_imports(
+    mains: &[&Path],
+    env: &Env,
+    display_src: bool,
+) -> Result<Vec<BTreeSet<MotokoImport>>> {
+    let cache_path = env.get_target_path().join("imports.json");
+    let moc_version = env.binary["moc"].get_version()?;
+    let mut cache = ImportCache::load(&cache_path, &moc_version);
+    let jobs = std::thread::available_parallelism().map_or(4, |n| n.get());
+    // Direct imports of every reachable local file
+    let mut graph: BTreeMap<PathBuf, Vec<MotokoImport>> = BTreeMap::new();
+    let mut queue: Vec<PathBuf> = mains.iter().map(|p| p.to_path_buf()).collect();
+    while !queue.is_empty() {
+        let mut pending = Vec::new();
+        for file in std::mem::take(&mut queue) {
+            if graph.contains_key(&file) || pending.contains(&file) {
+                continue;
+            }
+            // The imports of an unchanged file can still resolve differently, e.g., when ./lib.mo is moved to ./lib/lib.mo,
+            // so a cached entry that points at a missing file is analyzed again
+            match cache.get(&file)?.map(|deps| parse_deps(&deps)) {
+                Some(Ok(imports)) => {
+                    queue.extend(local_files(&imports));
+                    graph.insert(file, imports);
                 }
-                MotokoImport::Lib(lib) if lib == "⛔" => (),
-                _ => {
-                    result.insert(import);
+                Some(Err(_)) => {
+                    cache.files.remove(&file);
+                    pending.push(file);
                 }
+                None => pending.push(file),
             }
         }
-        Ok(())
+        let results: Vec<_> = futures::stream::iter(pending)
+            .map(|file| {
+                let mut command = env.binary["moc"].get_cmd();
+                command.arg("--print-deps").arg(&file);
+                if display_src {
+                    command.arg("--print-source-on-error");
+                }
+                tokio::task::spawn_blocking(move || (exec(command, true, None), file))
+            })
+            .buffer_unordered(jobs)
+            .collect()
+            .await;
+        for res in results {
+            let (deps, file) = res?;
+            let deps = deps?;
+            let imports = parse_deps(&deps)?;
+            cache.insert(&file, deps)?;
+            queue.extend(local_files(&imports));
+            graph.insert(file, imports);
+        }
     }
-    let mut result = BTreeSet::new();
-    get_imports_recursive(env, main_path, display_src, &mut result)?;
-    Ok(result)
+    std::fs::create_dir_all(env.get_target_path())?;
+    std::fs::write(&cache_path, serde_json::to_string(&cache)?)?;
+    let mut res = Vec::new();
+    for main in mains {
+        let mut result = BTreeSet::new();
+        let mut stack = vec![main.to_path_buf()];
+        while let Some(file) = stack.pop() {
+            if !result.insert(MotokoImport::Local(file.clone())) {
+                continue;
+            }
+            for import in &graph[&file] {
+                match import {
+                    MotokoImport::Local(path) => stack.push(path.clone()),
+                    _ => {
+                        result.insert(import.clone());
+                    }
+                }
+            }
+        }
+        res.push(result);
+    }
+    Ok(res)
+}
+fn parse_deps(deps: &str) -> Result<Vec<MotokoImport>> {
+    let mut res = Vec::new();
+    for line in deps.lines() {
+        let import = MotokoImport::try_from(line).context("Failed to parse import.")?;
+        match import {
+            MotokoImport::Lib(lib) if lib == "⛔" => (),
+            _ => res.push(import),
+        }
+    }
+    Ok(res)
+}
+fn local_files(imports: &[MotokoImport]) -> impl Iterator<Item = PathBuf> + '_ {
+    imports.iter().filter_map(|import| match import {
+        MotokoImport::Local(path) => Some(path.clone()),
+        _ => None,
+    })
 }
 
 impl TryFrom<&str> for MotokoImport {
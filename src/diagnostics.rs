@@ -0,0 +1,255 @@
+use console::style;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+    Info,
+}
+/// A moc error or warning, e.g. `main.mo:3.8-3.11: type error [M0057], unbound variable foo`
+#[derive(Serialize, Debug)]
+pub struct Diagnostic {
+    pub file: PathBuf,
+    pub line: usize,
+    pub column: usize,
+    pub end_line: usize,
+    pub end_column: usize,
+    pub severity: Severity,
+    /// The category reported by moc, e.g. "type error" or "syntax error"
+    pub kind: String,
+    pub code: Option<String>,
+    pub message: String,
+}
+pub struct Diagnostics {
+    pub items: Vec<Diagnostic>,
+    /// Output that is not part of any diagnostic
+    pub other: String,
+}
+
+impl Diagnostics {
+    pub fn parse(stderr: &str) -> Self {
+        let mut items: Vec<Diagnostic> = Vec::new();
+        let mut other = String::new();
+        for line in stderr.lines() {
+            if let Some(diag) = Diagnostic::parse_header(line) {
+                items.push(diag);
+            } else if let Some(last) = items.last_mut() {
+                // Multi-line messages, such as type mismatch explanations
+                last.message.push('\n');
+                last.message.push_str(line);
+            } else if !line.trim().is_empty() {
+                other.push_str(line);
+                other.push('\n');
+            }
+        }
+        for item in items.iter_mut() {
+            let len = item.message.trim_end().len();
+            item.message.truncate(len);
+        }
+        Self { items, other }
+    }
+    pub fn count(&self, severity: Severity) -> usize {
+        self.items.iter().filter(|d| d.severity == severity).count()
+    }
+}
+impl Diagnostic {
+    fn parse_header(line: &str) -> Option<Self> {
+        // The file name may contain ':', so try every position until the range parses
+        for (idx, _) in line.match_indices(':') {
+            let rest = &line[idx + 1..];
+            let Some((range, rest)) = rest.split_once(": ") else {
+                continue;
+            };
+            let Some((line_no, column, end_line, end_column)) = parse_range(range) else {
+                continue;
+            };
+            let (kind, message) = rest.split_once(", ").unwrap_or((rest, ""));
+            let (kind, code) = match kind.split_once(" [") {
+                Some((kind, code)) => (kind, code.strip_suffix(']').map(|c| c.to_string())),
+                None => (kind, None),
+            };
+            let severity = if kind.starts_with("warning") {
+                Severity::Warning
+            } else if kind.starts_with("info") {
+                Severity::Info
+            } else if kind.ends_with("error") {
+                Severity::Error
+            } else {
+                continue;
+            };
+            return Some(Diagnostic {
+                file: PathBuf::from(&line[..idx]),
+                line: line_no,
+                column,
+                end_line,
+                end_column,
+                severity,
+                kind: kind.to_string(),
+                code,
+                message: message.to_string(),
+            });
+        }
+        None
+    }
+    /// Render in the style of rustc, with the source snippet when the file is readable
+    pub fn render(&self) -> String {
+        let label = match self.severity {
+            Severity::Error => style(self.kind.as_str()).red().bold(),
+            Severity::Warning => style(self.kind.as_str()).yellow().bold(),
+            Severity::Info => style(self.kind.as_str()).cyan().bold(),
+        };
+        let code = self
+            .code
+            .as_ref()
+            .map(|c| format!("[{c}]"))
+            .unwrap_or_default();
+        let mut lines = self.message.lines();
+        let mut res = format!(
+            "{label}{}: {}\n",
+            style(code).bold(),
+            style(lines.next().unwrap_or_default()).bold()
+        );
+        for line in lines {
+            res.push_str(line);
+            res.push('\n');
+        }
+        let width = self.line.to_string().len();
+        let pad = " ".repeat(width);
+        res.push_str(&format!(
+            "{pad}{} {}:{}:{}\n",
+            style("-->").blue().bold(),
+            self.file.display(),
+            self.line,
+            self.column
+        ));
+        if let Some(src) = read_line(&self.file, self.line) {
+            let start = self.column.saturating_sub(1);
+            let end = if self.end_line == self.line && self.end_column > self.column {
+                self.end_column - 1
+            } else {
+                src.chars().count().max(start + 1)
+            };
+            let marker = "^".repeat(end - start);
+            let marker = match self.severity {
+                Severity::Error => style(marker).red().bold(),
+                Severity::Warning => style(marker).yellow().bold(),
+                Severity::Info => style(marker).cyan().bold(),
+            };
+            let bar = style("|").blue().bold();
+            res.push_str(&format!("{pad} {bar}\n"));
+            res.push_str(&format!("{} {bar} {src}\n", style(self.line).blue().bold()));
+            res.push_str(&format!("{pad} {bar} {}{marker}\n", " ".repeat(start)));
+        }
+        res
+    }
+}
+fn parse_range(range: &str) -> Option<(usize, usize, usize, usize)> {
+    let (start, end) = range.split_once('-')?;
+    let (line, column) = start.split_once('.')?;
+    let (end_line, end_column) = end.split_once('.')?;
+    Some((
+        line.parse().ok()?,
+        column.parse().ok()?,
+        end_line.parse().ok()?,
+        end_column.parse().ok()?,
+    ))
+}
+fn read_line(file: &Path, line: usize) -> Option<String> {
+    let content = std::fs::read_to_string(file).ok()?;
+    content
+        .lines()
+        .nth(line.checked_sub(1)?)
+        .map(|l| l.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Diagnostic, Diagnostics, Severity};
+    use std::path::Path;
+
+    #[test]
+    fn type_error_with_code() {
+        let diag = Diagnostic::parse_header(
+            "src/main.mo:3.8-3.11: type error [M0057], unbound variable foo",
+        )
+        .unwrap();
+        assert_eq!(diag.file, Path::new("src/main.mo"));
+        assert_eq!(
+            (diag.line, diag.column, diag.end_line, diag.end_column),
+            (3, 8, 3, 11)
+        );
+        assert_eq!(diag.severity, Severity::Error);
+        assert_eq!(diag.kind, "type error");
+        assert_eq!(diag.code.as_deref(), Some("M0057"));
+        assert_eq!(diag.message, "unbound variable foo");
+    }
+
+    #[test]
+    fn warning() {
+        let diag =
+            Diagnostic::parse_header("main.mo:2.1-2.6: warning [M0194], unused identifier x")
+                .unwrap();
+        assert_eq!(diag.severity, Severity::Warning);
+        assert_eq!(diag.kind, "warning");
+        assert_eq!(diag.code.as_deref(), Some("M0194"));
+        // No code and no message
+        let diag = Diagnostic::parse_header("main.mo:1.1-1.2: syntax error").unwrap();
+        assert_eq!(diag.severity, Severity::Error);
+        assert_eq!(diag.code, None);
+        assert_eq!(diag.message, "");
+    }
+
+    #[test]
+    fn file_path_with_colon() {
+        let diag = Diagnostic::parse_header(
+            "C:\\project\\a:b.mo:10.2-12.5: type error [M0096], expression of type",
+        )
+        .unwrap();
+        assert_eq!(diag.file, Path::new("C:\\project\\a:b.mo"));
+        assert_eq!(
+            (diag.line, diag.column, diag.end_line, diag.end_column),
+            (10, 2, 12, 5)
+        );
+        // A colon followed by something other than a range is not a diagnostic
+        assert!(Diagnostic::parse_header("note: see https://example.com:8080/doc").is_none());
+        assert!(Diagnostic::parse_header("main.mo:3.8-3.11: note, something").is_none());
+    }
+
+    #[test]
+    fn multi_line_message_and_other_output() {
+        let stderr = "moc: some banner\n\
+            main.mo:2.1-2.6: warning [M0194], unused identifier x\n\
+            main.mo:1.8-1.9: type error [M0096], expression of type\n  Nat\ncannot produce expected type\n  Text\n\n";
+        let diags = Diagnostics::parse(stderr);
+        assert_eq!(diags.other, "moc: some banner\n");
+        assert_eq!(diags.items.len(), 2);
+        assert_eq!(diags.items[0].message, "unused identifier x");
+        assert_eq!(
+            diags.items[1].message,
+            "expression of type\n  Nat\ncannot produce expected type\n  Text"
+        );
+        assert_eq!(diags.count(Severity::Error), 1);
+        assert_eq!(diags.count(Severity::Warning), 1);
+    }
+
+    #[test]
+    fn render_marks_one_based_columns() {
+        console::set_colors_enabled(false);
+        let file = std::env::temp_dir().join(format!("mops-cli-diag-{}.mo", std::process::id()));
+        std::fs::write(&file, "actor {\n  let x = foo;\n}\n").unwrap();
+        let header = format!(
+            "{}:2.11-2.14: type error [M0057], unbound variable foo",
+            file.display()
+        );
+        let rendered = Diagnostic::parse_header(&header).unwrap().render();
+        std::fs::remove_file(&file).unwrap();
+        let lines: Vec<_> = rendered.lines().collect();
+        assert_eq!(lines[0], "type error[M0057]: unbound variable foo");
+        assert_eq!(lines[1], format!(" --> {}:2:11", file.display()));
+        assert_eq!(lines[3], "2 |   let x = foo;");
+        assert_eq!(lines[4], "  |           ^^^");
+    }
+}
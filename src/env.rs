@@ -1,16 +1,36 @@
 use crate::binary_cache::*;
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
+use ic_agent::Agent;
 use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static OFFLINE: AtomicBool = AtomicBool::new(false);
 
 pub struct Env {
     pub cache_dir: PathBuf,
     pub project_root: PathBuf,
     pub toolchain: BTreeMap<String, String>,
     pub binary: BTreeMap<String, Box<dyn Binary>>,
+    /// Directory registry from --registry or mops.toml. None means the mops canister.
+    pub registry: Option<PathBuf>,
+    pub network: Network,
+}
+pub struct Network {
+    /// `ic`, `local`, a name in the [network] section of mops.toml, or the url itself
+    pub name: String,
+    pub url: String,
+    /// Only local replicas are not signed by the IC root key, unless opted in by `fetch_root_key = true`
+    pub fetch_root_key: bool,
 }
 impl Env {
-    pub async fn new(cache_dir: &Option<PathBuf>) -> Result<Self> {
+    pub async fn new(
+        cache_dir: &Option<PathBuf>,
+        offline: bool,
+        registry: &Option<PathBuf>,
+        network: &Option<String>,
+    ) -> Result<Self> {
+        OFFLINE.store(offline, Ordering::Relaxed);
         let cache_dir = get_cache_dir(cache_dir)?;
         let project_root = find_project_root()?;
         let mut res = Self {
@@ -18,8 +38,15 @@ impl Env {
             project_root,
             toolchain: BTreeMap::new(),
             binary: BTreeMap::new(),
+            registry: registry.clone(),
+            network: Network::ic(),
         };
-        res.get_toolchain()?;
+        res.get_config(network)?;
+        if let Some(dir) = &res.registry {
+            let dir = std::fs::canonicalize(dir)
+                .map_err(|_| anyhow!("Cannot find registry directory {}", dir.display()))?;
+            res.registry = Some(dir);
+        }
         res.binary.insert(
             "moc".to_owned(),
             Box::new(Moc {
@@ -34,8 +61,20 @@ impl Env {
                 expect_version: res.toolchain.get("mo-fmt").cloned(),
             }),
         );
-        res.binary["moc"].update_binary(false).await?;
-        res.binary["mo-fmt"].update_binary(false).await?;
+        // ic-wasm is only downloaded when the [wasm] pipeline needs it
+        res.binary.insert(
+            "ic-wasm".to_owned(),
+            Box::new(IcWasm {
+                binary_path: res.get_binary_path(),
+                expect_version: res.toolchain.get("ic-wasm").cloned(),
+            }),
+        );
+        if offline {
+            res.check_cached_moc()?;
+        } else {
+            res.binary["moc"].update_binary(false).await?;
+            res.binary["mo-fmt"].update_binary(false).await?;
+        }
         Ok(res)
     }
     pub fn get_mops_toml_path(&self) -> PathBuf {
@@ -47,6 +86,9 @@ impl Env {
     pub fn get_target_path(&self) -> PathBuf {
         self.project_root.join("target")
     }
+    pub fn get_test_path(&self) -> PathBuf {
+        self.project_root.join("test")
+    }
     pub fn get_target_idl_path(&self) -> PathBuf {
         self.get_target_path().join("idl")
     }
@@ -57,14 +99,98 @@ impl Env {
         let filename = format!("{name}.wasm");
         self.get_target_path().join(name).join(filename)
     }
+    /// (name, target/<name>/<name>.<ext>) of the given target, or of all built targets when name is None
+    pub fn get_built_files(
+        &self,
+        name: &Option<String>,
+        ext: &str,
+    ) -> Result<Vec<(String, PathBuf)>> {
+        let target = self.get_target_path();
+        let file = |name: &str| target.join(name).join(format!("{name}.{ext}"));
+        if let Some(name) = name {
+            let path = file(name);
+            if !path.exists() {
+                return Err(anyhow!(
+                    "Cannot find {}. Run mops-cli build first.",
+                    path.display()
+                ));
+            }
+            return Ok(vec![(name.clone(), path)]);
+        }
+        let mut res = Vec::new();
+        if target.is_dir() {
+            for entry in std::fs::read_dir(&target)? {
+                let name = entry?.file_name().to_string_lossy().to_string();
+                let path = file(&name);
+                if path.exists() {
+                    res.push((name, path));
+                }
+            }
+        }
+        if res.is_empty() {
+            return Err(anyhow!(
+                "Cannot find any .{ext} file in {}. Run mops-cli build first.",
+                target.display()
+            ));
+        }
+        res.sort();
+        Ok(res)
+    }
     pub fn get_binary_path(&self) -> PathBuf {
         self.cache_dir.join("bin")
     }
-    fn get_toolchain(&mut self) -> Result<()> {
+    fn check_cached_moc(&self) -> Result<()> {
+        let moc = &self.binary["moc"];
+        let ver = moc.get_version().map_err(|_| {
+            anyhow!(
+                "moc is not found in {}. Run without --offline to download it.",
+                self.get_binary_path().display()
+            )
+        })?;
+        if let Some(exp_ver) = moc.get_expect_version() {
+            if *exp_ver != ver {
+                return Err(anyhow!("mops.toml requires moc {exp_ver}, but the cached moc is {ver}. Run without --offline to download it."));
+            }
+        }
+        Ok(())
+    }
+    /// Read [toolchain] and [registry] from mops.toml
+    fn get_config(&mut self, network: &Option<String>) -> Result<()> {
         let toml = self.get_mops_toml_path();
+        let mut networks = BTreeMap::new();
+        let mut default = None;
         if toml.exists() {
             let toml = std::fs::read_to_string(toml)?;
             let toml = toml.parse::<toml_edit::ImDocument<_>>()?;
+            if self.registry.is_none() {
+                if let Some(path) = toml
+                    .get("registry")
+                    .and_then(|r| r.get("path"))
+                    .and_then(|p| p.as_str())
+                {
+                    self.registry = Some(self.project_root.join(path));
+                }
+            }
+            if let Some(table) = toml.get("network").and_then(|n| n.as_table()) {
+                for (k, v) in table {
+                    if k == "default" {
+                        default = v.as_str().map(|v| v.to_owned());
+                    } else if let Some(url) = v.as_str() {
+                        networks.insert(k.to_owned(), (url.to_owned(), None));
+                    } else if let Some(t) = v.as_table_like() {
+                        let url = t.get("url").and_then(|u| u.as_str()).ok_or_else(|| {
+                            anyhow!("url is required for network {k} in [network]")
+                        })?;
+                        let fetch_root_key = match t.get("fetch_root_key") {
+                            None => None,
+                            Some(b) => Some(b.as_bool().ok_or_else(|| {
+                                anyhow!("fetch_root_key should be a boolean for network {k}")
+                            })?),
+                        };
+                        networks.insert(k.to_owned(), (url.to_owned(), fetch_root_key));
+                    }
+                }
+            }
             if let Some(toolchain) = toml.get("toolchain") {
                 if let Some(toolchain) = toolchain.as_table() {
                     for (k, v) in toolchain {
@@ -75,10 +201,74 @@ impl Env {
                 }
             }
         }
+        let name = network.clone().or(default).unwrap_or("ic".to_string());
+        self.network = Network::new(&name, &networks)?;
         Ok(())
     }
 }
 
+impl Network {
+    fn ic() -> Self {
+        Network {
+            name: "ic".to_string(),
+            url: "https://icp0.io".to_string(),
+            fetch_root_key: false,
+        }
+    }
+    /// `networks` maps a name in [network] to its url and the optional `fetch_root_key` setting
+    fn new(name: &str, networks: &BTreeMap<String, (String, Option<bool>)>) -> Result<Self> {
+        let (url, fetch_root_key) = match (name, networks.get(name)) {
+            (_, Some((url, fetch_root_key))) => (url.clone(), *fetch_root_key),
+            ("ic", None) => return Ok(Network::ic()),
+            ("local", None) => ("http://127.0.0.1:4943".to_string(), Some(true)),
+            (url, None) if url.starts_with("http://") || url.starts_with("https://") => {
+                (url.to_string(), None)
+            }
+            _ => {
+                return Err(anyhow!(
+                    "Unknown network {name}. Use ic, local, a url, or add {name} = \"<url>\" to the [network] section of mops.toml."
+                ))
+            }
+        };
+        let parsed = url::Url::parse(&url)
+            .map_err(|e| anyhow!("Invalid url {url} for network {name}: {e}"))?;
+        // Never trust the root key served by a remote host, otherwise the certificates are not verified against the IC
+        let is_loopback = match parsed.host() {
+            Some(url::Host::Domain(domain)) => domain == "localhost",
+            Some(url::Host::Ipv4(ip)) => ip.is_loopback(),
+            Some(url::Host::Ipv6(ip)) => ip.is_loopback(),
+            None => false,
+        };
+        Ok(Network {
+            name: name.to_string(),
+            url,
+            fetch_root_key: fetch_root_key.unwrap_or(name == "local" || is_loopback),
+        })
+    }
+    /// Call this before the first request to the network
+    pub async fn connect(&self, agent: &Agent) -> Result<()> {
+        if self.fetch_root_key {
+            agent
+                .fetch_root_key()
+                .await
+                .with_context(|| format!("Cannot connect to network {}", self.url))?;
+        }
+        Ok(())
+    }
+}
+pub fn is_offline() -> bool {
+    OFFLINE.load(Ordering::Relaxed)
+}
+/// Call this before any network request, so that --offline fails fast.
+pub fn check_online(action: &str) -> Result<()> {
+    if is_offline() {
+        Err(anyhow!(
+            "Cannot {action} in offline mode. Run without --offline to access the network."
+        ))
+    } else {
+        Ok(())
+    }
+}
 fn find_project_root() -> Result<PathBuf> {
     let mut path = std::env::current_dir()?;
     loop {
@@ -97,19 +287,21 @@ pub fn get_cache_dir(base_path: &Option<PathBuf>) -> Result<PathBuf> {
     } else if let Ok(home) = std::env::var("HOME") {
         Ok(PathBuf::from(home).join(".mops"))
     } else {
-        return Err(anyhow!(
+        Err(anyhow!(
             "Cannot find home directory, use --cache_dir to specify the cache directory."
-        ));
+        ))
     }
 }
-pub fn guess_main_file() -> Result<PathBuf> {
-    if Path::new("main.mo").exists() {
-        Ok(PathBuf::from("main.mo"))
-    } else if Path::new("Main.mo").exists() {
-        Ok(PathBuf::from("Main.mo"))
+pub fn guess_main_file(dir: &Path) -> Result<PathBuf> {
+    if dir.join("main.mo").exists() {
+        Ok(dir.join("main.mo"))
+    } else if dir.join("Main.mo").exists() {
+        Ok(dir.join("Main.mo"))
     } else {
+        let dir = std::fs::canonicalize(dir).unwrap_or(dir.to_path_buf());
         Err(anyhow!(
-            "Cannot find main.mo or Main.mo, please specify the main file."
+            "Cannot find main.mo or Main.mo in {}, please specify the main file.",
+            dir.display()
         ))
     }
 }
@@ -120,7 +312,7 @@ fn guess_build_name(main_file: &PathBuf) -> Option<String> {
         main_file
             .parent()?
             .components()
-            .last()?
+            .next_back()?
             .as_os_str()
             .to_str()?
             .to_owned()
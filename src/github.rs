@@ -1,4 +1,5 @@
-use crate::utils::println;
+use crate::env::check_online;
+use crate::utils::{emit, println, Event};
 use anyhow::Result;
 use console::style;
 use futures::future::try_join_all;
@@ -63,6 +64,7 @@ pub async fn parse_github_url(url: &str) -> Result<RepoInfo> {
 
 pub async fn download_github_package(
     base_path: PathBuf,
+    name: String,
     repo: RepoInfo,
     bar: Rc<ProgressBar>,
 ) -> Result<()> {
@@ -83,6 +85,10 @@ pub async fn download_github_package(
             repo.tag
         ),
     );
+    emit(Event::PackageDownloaded {
+        name: &name,
+        version: &repo.tag,
+    });
     bar.inc(1);
     Ok(())
 }
@@ -188,6 +194,7 @@ async fn get_file_list(repo: &RepoInfo) -> Result<Vec<String>> {
         .collect())
 }
 async fn github_request(url: &str) -> Result<String> {
+    check_online(&format!("fetch {url}"))?;
     let client = reqwest::Client::new();
     let mut request = client.get(url).header("User-Agent", "mops-cli");
     if let Ok(token) = std::env::var("GITHUB_TOKEN") {
@@ -197,7 +204,7 @@ async fn github_request(url: &str) -> Result<String> {
     let body = response.text().await?;
     Ok(body)
 }
-fn guess_version_from_tag(tag: &str) -> Option<Version> {
+pub fn guess_version_from_tag(tag: &str) -> Option<Version> {
     let idx = tag.find(|c: char| c.is_ascii_digit())?;
     let maybe = &tag[idx..];
     maybe.parse::<Version>().ok()
@@ -3,13 +3,21 @@ use clap::Parser;
 use std::path::PathBuf;
 
 mod binary_cache;
+mod bindgen;
 mod build;
+mod diagnostics;
 mod env;
 mod github;
 mod mops;
+mod registry;
+mod resolver;
 mod storage;
+mod test;
 mod toml;
+mod tree;
+mod upgrade;
 mod utils;
+mod wasm;
 
 use crate::utils::exec;
 #[derive(Parser)]
@@ -17,6 +25,18 @@ struct Opts {
     #[arg(short, long)]
     /// Directory to cache external dependencies
     cache_dir: Option<PathBuf>,
+    #[arg(long, global = true)]
+    /// Do not access the network. Packages and the Motoko compiler are resolved from the cache directory and mops.lock
+    offline: bool,
+    #[arg(long, global = true, value_enum, default_value = "human")]
+    /// Output format of progress and results
+    message_format: utils::MessageFormat,
+    #[arg(long, global = true)]
+    /// Use a local directory as the package registry instead of mops. Packages are stored in <dir>/<name>/<version>/
+    registry: Option<PathBuf>,
+    #[arg(long, global = true)]
+    /// The network to fetch canister interfaces and packages from: ic, local, a name in the [network] section of mops.toml, or a url. Defaults to ic.
+    network: Option<String>,
     #[command(subcommand)]
     cmd: ClapCommand,
 }
@@ -31,12 +51,98 @@ enum ClapCommand {
     Update(UpdateArg),
     /// Motoko formatter
     Fmt(FmtArg),
+    /// Run Motoko tests in test/**/*.test.mo
+    Test(TestArg),
+    /// Add a dependency to mops.toml
+    Add(AddArg),
+    /// Remove a dependency from mops.toml
+    Remove(RemoveArg),
+    /// Display the dependency tree in mops.lock
+    Tree(TreeArg),
+    /// Explain why a package is in mops.lock and which version is selected
+    Why(WhyArg),
+    /// Generate the bindings in the [bindings] section of mops.toml from the built candid files
+    Bindgen(BindgenArg),
+    /// Check that the stable variables of the built canister can be upgraded safely
+    CheckUpgrade(CheckUpgradeArg),
+    /// Check that the candid interface of the built canister is backward compatible
+    CheckCandid(CheckCandidArg),
+    /// Manage the canisters imported in mops.toml
+    #[command(subcommand)]
+    Canister(CanisterCommand),
+}
+#[derive(Parser)]
+enum CanisterCommand {
+    /// Fetch the candid interface of remote canisters again and compare it with mops.lock
+    Refresh {
+        /// Canister name or id. Refreshes all remote canisters when omitted.
+        name: Option<String>,
+    },
+}
+#[derive(Parser)]
+pub struct AddArg {
+    /// Package name, optionally with a version, e.g. base@0.11.3. Defaults to the highest version on mops.
+    pub name: String,
+    #[arg(long, conflicts_with = "path")]
+    /// Github url of the package, e.g. https://github.com/owner/repo#v1.0.0
+    pub git: Option<String>,
+    #[arg(long)]
+    /// Local directory of the package
+    pub path: Option<PathBuf>,
 }
 #[derive(Parser)]
-struct UpdateArg {
+pub struct RemoveArg {
+    /// Package name
+    pub name: String,
+}
+#[derive(Parser)]
+pub struct TreeArg {
+    #[arg(short, long)]
+    /// Show the packages that depend on the given package
+    pub invert: Option<String>,
+    #[arg(long, value_enum, default_value = "text")]
+    /// Output format of the tree
+    pub format: tree::TreeFormat,
+}
+#[derive(Parser)]
+pub struct WhyArg {
+    /// Package name
+    pub package: String,
+}
+#[derive(Parser)]
+pub struct BindgenArg {
+    /// Target name, i.e., target/<name>/<name>.did. Generates bindings for all built targets when omitted.
+    pub name: Option<String>,
+}
+#[derive(Parser)]
+pub struct CheckUpgradeArg {
+    /// Target name, i.e., target/<name>/<name>.most. Can be omitted when only one target is built.
+    pub name: Option<String>,
+    #[arg(long)]
+    /// The .most file of the previous build, or the id of the deployed canister to read the motoko:stable-types metadata from
+    pub against: String,
+}
+#[derive(Parser)]
+pub struct CheckCandidArg {
+    /// Target name, i.e., target/<name>/<name>.did. Can be omitted when only one target is built.
+    pub name: Option<String>,
+    #[arg(long)]
+    /// The .did file of the previous build, or the id of the deployed canister to read the candid:service metadata from
+    pub against: String,
+}
+#[derive(Parser)]
+pub struct UpdateArg {
     #[arg(short, long)]
     /// Download the latest Motoko compiler
     pub moc: bool,
+    /// Packages to update. Updates all dependencies when omitted.
+    pub packages: Vec<String>,
+    #[arg(long)]
+    /// Only update to versions with the same major version
+    pub compatible: bool,
+    #[arg(long)]
+    /// Print the available updates without changing mops.toml and mops.lock
+    pub dry_run: bool,
 }
 #[derive(Parser)]
 struct FmtArg {
@@ -51,18 +157,41 @@ struct MocArg {
     extra_args: Vec<String>,
 }
 #[derive(Parser)]
+pub struct TestArg {
+    /// Only run test files whose path contains this string
+    pub filter: Option<String>,
+    #[arg(long, value_enum, default_value = "interpreter")]
+    /// How to run the tests
+    pub mode: test::TestMode,
+    #[arg(long, default_value = "wasmtime")]
+    /// The wasm runtime used in wasi mode
+    pub runtime: PathBuf,
+    #[arg(long)]
+    /// Lock the dependencies
+    pub lock: bool,
+}
+#[derive(Parser)]
 pub struct BuildArg {
-    /// The path to the main Motoko file
+    /// The path to the main Motoko file, or the name of a [[bin]] target in mops.toml. Builds all [[bin]] targets when omitted.
     pub main: Option<PathBuf>,
     #[arg(short, long)]
     /// Output Wasm file path at target/<name>/. Can be overridden by specifying "-- -o output.wasm".
     pub name: Option<String>,
+    #[arg(long, conflicts_with_all = ["main", "name"])]
+    /// Build all members in the [workspace] section of mops.toml in parallel
+    pub workspace: bool,
     #[arg(long)]
     /// Lock the dependencies
     pub lock: bool,
     #[arg(short, long)]
-    /// Display the source code for error messages
+    /// Display the source code for error messages when analyzing dependencies. Compiler errors always display the source code.
     pub print_source_on_error: bool,
+    #[arg(long)]
+    /// Fail the build when the compiler reports any warnings
+    pub deny_warnings: bool,
+    #[arg(long, value_name = "DID_OR_CANISTER_ID")]
+    /// After building, check that the candid interface is backward compatible with a .did file or a deployed canister
+    pub check_candid: Option<String>,
     #[clap(last = true)]
     /// Extra arguments passed to moc. Need to add "--" before the arguments. Default args are "--release --idl --stable-types --public-metadata candid:service". When extra arguments are provided, the default args are not included.
     extra_args: Vec<String>,
@@ -71,9 +200,19 @@ pub struct BuildArg {
 #[tokio::main(flavor = "multi_thread", worker_threads = 10)]
 async fn main() -> Result<()> {
     let opts = Opts::parse();
-    let env = env::Env::new(&opts.cache_dir).await?;
+    utils::set_message_format(opts.message_format);
+    let res = run(opts).await;
+    if let Err(e) = &res {
+        utils::emit(utils::Event::Error {
+            message: &format!("{e:#}"),
+        });
+    }
+    res
+}
+async fn run(opts: Opts) -> Result<()> {
+    let env = env::Env::new(&opts.cache_dir, opts.offline, &opts.registry, &opts.network).await?;
     let agent = ic_agent::Agent::builder()
-        .with_url("https://icp0.io")
+        .with_url(&env.network.url)
         .build()?;
     match opts.cmd {
         ClapCommand::Moc(args) => {
@@ -84,13 +223,44 @@ async fn main() -> Result<()> {
         ClapCommand::Build(args) => {
             build::build(&agent, &env, args).await?;
         }
+        ClapCommand::Add(args) => {
+            toml::add_dependency(&agent, &env, args).await?;
+        }
+        ClapCommand::Remove(args) => {
+            toml::remove_dependency(&agent, &env, args).await?;
+        }
+        ClapCommand::Tree(args) => {
+            tree::tree(&env, args)?;
+        }
+        ClapCommand::Why(args) => {
+            tree::why(&env, args)?;
+        }
+        ClapCommand::Bindgen(args) => {
+            bindgen::bindgen(&env, args)?;
+        }
+        ClapCommand::CheckUpgrade(args) => {
+            upgrade::check_upgrade(&agent, &env, args).await?;
+        }
+        ClapCommand::CheckCandid(args) => {
+            upgrade::check_candid(&agent, &env, args).await?;
+        }
+        ClapCommand::Canister(CanisterCommand::Refresh { name }) => {
+            toml::refresh_canisters(&agent, &env, name).await?;
+        }
+        ClapCommand::Test(args) => {
+            test::test(&agent, &env, args).await?;
+        }
         ClapCommand::Update(args) => {
             if args.moc {
                 for bin in env.binary.values() {
+                    // ic-wasm is only updated after the [wasm] pipeline has downloaded it
+                    if bin.name() == "ic-wasm" && bin.get_version().is_err() {
+                        continue;
+                    }
                     bin.update_binary(true).await?;
                 }
             } else {
-                toml::update_packages_from_lock(&agent, &env).await?;
+                toml::update_packages_from_lock(&agent, &env, args).await?;
             }
         }
         ClapCommand::Fmt(args) => {
@@ -0,0 +1,285 @@
+use crate::env::{check_online, Env, Network};
+use crate::{mops, storage};
+use anyhow::{anyhow, Error, Result};
+use async_trait::async_trait;
+use candid::Principal;
+use ic_agent::Agent;
+use semver::Version;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// The information of a published package that is recorded in mops.lock
+pub struct PackageInfo {
+    /// Where the files are stored. Passed back to `download_file`.
+    pub source: String,
+    pub base_dir: String,
+    pub dependencies: BTreeMap<String, String>,
+}
+
+#[async_trait(?Send)]
+pub trait Registry {
+    /// Returns None if the package does not exist
+    async fn get_highest_version(&self, name: &str) -> Result<Option<String>>;
+    /// All published versions of the package. Empty if the package does not exist.
+    async fn list_versions(&self, name: &str) -> Result<Vec<Version>>;
+    /// The highest version with the same major version for each (name, version)
+    async fn get_highest_compatible_versions(
+        &self,
+        pkgs: Vec<(String, String)>,
+    ) -> Result<BTreeMap<String, String>>;
+    async fn get_package_details(&self, name: &str, version: &str) -> Result<PackageInfo>;
+    async fn get_file_ids(&self, name: &str, version: &str) -> Result<Vec<String>>;
+    /// Returns the path of the file relative to the package root and its content
+    async fn download_file(&self, source: &str, id: &str) -> Result<(String, Vec<u8>)>;
+    /// Whether the source of a package in mops.lock is stored in this registry
+    fn has_source(&self, source: &str) -> bool;
+    fn describe(&self) -> String;
+}
+
+/// Use the directory registry if configured, otherwise the mops canister
+pub fn get_registry<'a>(env: &'a Env, agent: &'a Agent) -> Rc<dyn Registry + 'a> {
+    match &env.registry {
+        Some(dir) => Rc::new(DirRegistry(dir.clone())),
+        None => Rc::new(IcRegistry(
+            mops::Service(mops::CANISTER_ID, agent),
+            &env.network,
+        )),
+    }
+}
+
+pub struct IcRegistry<'a>(mops::Service<'a>, &'a Network);
+impl IcRegistry<'_> {
+    async fn connect(&self, action: &str) -> Result<()> {
+        check_online(action)?;
+        self.1.connect(self.0 .1).await
+    }
+}
+#[async_trait(?Send)]
+impl Registry for IcRegistry<'_> {
+    async fn get_highest_version(&self, name: &str) -> Result<Option<String>> {
+        self.connect(&format!("find {name} on mops")).await?;
+        let res = self.0.get_highest_version(&name.to_string()).await?;
+        Ok(res.into_result().ok())
+    }
+    async fn list_versions(&self, name: &str) -> Result<Vec<Version>> {
+        let Some(highest) = self.get_highest_version(name).await? else {
+            return Ok(Vec::new());
+        };
+        let pkg = self
+            .0
+            .get_package_details(&name.to_string(), &highest)
+            .await?
+            .into_result()
+            .map_err(Error::msg)?;
+        let mut res: Vec<_> = pkg
+            .version_history
+            .iter()
+            .map(|p| p.config.version.as_str())
+            .chain([highest.as_str()])
+            .filter_map(|v| v.parse::<Version>().ok())
+            .collect();
+        res.sort();
+        res.dedup();
+        Ok(res)
+    }
+    async fn get_highest_compatible_versions(
+        &self,
+        pkgs: Vec<(String, String)>,
+    ) -> Result<BTreeMap<String, String>> {
+        self.connect("check for updates on mops").await?;
+        let batch = pkgs
+            .into_iter()
+            .map(|(name, ver)| (name, ver, mops::SemverPart::Major))
+            .collect();
+        let res = self
+            .0
+            .get_highest_semver_batch(&batch)
+            .await?
+            .into_result()
+            .map_err(Error::msg)?;
+        Ok(res.into_iter().collect())
+    }
+    async fn get_package_details(&self, name: &str, version: &str) -> Result<PackageInfo> {
+        self.connect(&format!("fetch {name}@{version} from mops"))
+            .await?;
+        let pkg = self
+            .0
+            .get_package_details(&name.to_string(), &version.to_string())
+            .await?
+            .into_result()
+            .map_err(Error::msg)?;
+        let dependencies = pkg
+            .config
+            .dependencies
+            .into_iter()
+            .map(|d| {
+                let spec = if d.version.is_empty() {
+                    d.repo
+                } else {
+                    d.version
+                };
+                (d.name, spec)
+            })
+            .collect();
+        Ok(PackageInfo {
+            source: pkg.publication.storage.to_string(),
+            base_dir: pkg.config.base_dir,
+            dependencies,
+        })
+    }
+    async fn get_file_ids(&self, name: &str, version: &str) -> Result<Vec<String>> {
+        self.connect(&format!("download {name}@{version}")).await?;
+        self.0
+            .get_file_ids(&name.to_string(), &version.to_string())
+            .await?
+            .into_result()
+            .map_err(Error::msg)
+    }
+    async fn download_file(&self, source: &str, id: &str) -> Result<(String, Vec<u8>)> {
+        let storage = storage::Service(Principal::from_text(source)?, self.0 .1);
+        let id = id.to_string();
+        let meta = storage
+            .get_file_meta(&id)
+            .await?
+            .into_result()
+            .map_err(Error::msg)?;
+        let mut blob = Vec::new();
+        for i in 0..meta.chunk_count {
+            let chunk = storage
+                .download_chunk(&id, &i.into())
+                .await?
+                .into_result()
+                .map_err(Error::msg)?;
+            blob.extend(chunk);
+        }
+        Ok((meta.path, blob))
+    }
+    fn has_source(&self, source: &str) -> bool {
+        Principal::from_text(source).is_ok()
+    }
+    fn describe(&self) -> String {
+        format!("the mops canister on {}", self.1.name)
+    }
+}
+
+/// A registry in a local directory, with packages stored in `<name>/<version>/`.
+/// Each package directory has the same layout as a published package, i.e., `mops.toml` and the source files.
+pub struct DirRegistry(pub PathBuf);
+impl DirRegistry {
+    fn get_versions(&self, name: &str) -> Result<Vec<Version>> {
+        let dir = self.0.join(name);
+        if !dir.is_dir() {
+            return Ok(Vec::new());
+        }
+        let mut res = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            if let Ok(ver) = entry?.file_name().to_string_lossy().parse::<Version>() {
+                res.push(ver);
+            }
+        }
+        res.sort();
+        Ok(res)
+    }
+    fn get_package_dir(&self, name: &str, version: &str) -> Result<PathBuf> {
+        let dir = self.0.join(name).join(version);
+        if !dir.is_dir() {
+            return Err(anyhow!(
+                "Cannot find {name}@{version} in registry {}",
+                self.0.display()
+            ));
+        }
+        Ok(dir)
+    }
+}
+#[async_trait(?Send)]
+impl Registry for DirRegistry {
+    async fn get_highest_version(&self, name: &str) -> Result<Option<String>> {
+        Ok(self.get_versions(name)?.pop().map(|v| v.to_string()))
+    }
+    async fn list_versions(&self, name: &str) -> Result<Vec<Version>> {
+        self.get_versions(name)
+    }
+    async fn get_highest_compatible_versions(
+        &self,
+        pkgs: Vec<(String, String)>,
+    ) -> Result<BTreeMap<String, String>> {
+        let mut res = BTreeMap::new();
+        for (name, ver) in pkgs {
+            let Ok(ver) = ver.parse::<Version>() else {
+                continue;
+            };
+            if let Some(latest) = self
+                .get_versions(&name)?
+                .into_iter()
+                .rfind(|v| v.major == ver.major)
+            {
+                res.insert(name, latest.to_string());
+            }
+        }
+        Ok(res)
+    }
+    async fn get_package_details(&self, name: &str, version: &str) -> Result<PackageInfo> {
+        let dir = self.get_package_dir(name, version)?;
+        let mut base_dir = "src".to_string();
+        let mut dependencies = BTreeMap::new();
+        let toml = dir.join("mops.toml");
+        if toml.exists() {
+            let doc = fs::read_to_string(toml)?.parse::<toml_edit::ImDocument<_>>()?;
+            if let Some(dir) = doc
+                .get("package")
+                .and_then(|p| p.get("baseDir"))
+                .and_then(|d| d.as_str())
+            {
+                base_dir = dir.to_string();
+            }
+            if let Some(deps) = doc.get("dependencies").and_then(|d| d.as_table()) {
+                for (name, spec) in deps {
+                    let spec = spec
+                        .as_str()
+                        .ok_or_else(|| anyhow!("invalid version for {name}"))?;
+                    dependencies.insert(name.to_string(), spec.to_string());
+                }
+            }
+        }
+        Ok(PackageInfo {
+            source: format!("dir://{}", self.0.display()),
+            base_dir,
+            dependencies,
+        })
+    }
+    async fn get_file_ids(&self, name: &str, version: &str) -> Result<Vec<String>> {
+        fn walk(root: &Path, dir: &Path, res: &mut Vec<String>) -> Result<()> {
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    walk(root, &path, res)?;
+                } else {
+                    let path = path.strip_prefix(root)?;
+                    res.push(path.to_string_lossy().replace('\\', "/"));
+                }
+            }
+            Ok(())
+        }
+        let dir = self.get_package_dir(name, version)?;
+        let mut res = Vec::new();
+        walk(&self.0, &dir, &mut res)?;
+        res.sort();
+        Ok(res)
+    }
+    async fn download_file(&self, _source: &str, id: &str) -> Result<(String, Vec<u8>)> {
+        // The id is <name>/<version>/<path>
+        let path = id
+            .splitn(3, '/')
+            .nth(2)
+            .ok_or_else(|| anyhow!("invalid file id {id}"))?;
+        Ok((path.to_string(), fs::read(self.0.join(id))?))
+    }
+    fn has_source(&self, source: &str) -> bool {
+        source == format!("dir://{}", self.0.display())
+    }
+    fn describe(&self) -> String {
+        format!("registry {}", self.0.display())
+    }
+}
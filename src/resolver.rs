@@ -0,0 +1,334 @@
+use anyhow::{anyhow, Result};
+use console::style;
+use semver::{Version, VersionReq};
+use std::collections::BTreeMap;
+
+/// What a dependency entry in `mops.toml` asks for.
+#[derive(Debug, Clone)]
+pub enum Constraint {
+    /// Semver range of a mops package, e.g. `0.11.3` (caret), `^1.2`, `~1.2.3` or `=1.0.0`.
+    Range(VersionReq),
+    /// A specific package, identified by its key in `mops.lock`. Used for github and local packages.
+    Source(String),
+}
+#[derive(Debug, Clone)]
+pub struct Requirement {
+    pub name: String,
+    /// The original string from `mops.toml`, only used for error messages.
+    pub spec: String,
+    pub constraint: Constraint,
+}
+#[derive(Debug)]
+pub struct Candidate {
+    pub key: String,
+    pub label: String,
+    pub version: Option<Version>,
+    pub dependencies: Vec<Requirement>,
+}
+pub struct Resolution {
+    /// Package name to the key of the selected package
+    pub selected: BTreeMap<String, String>,
+    /// Transitive requirements that are not satisfied, because mops.toml pins the package: (requester, requirement, selected label)
+    pub overridden: Vec<(String, Requirement, String)>,
+}
+struct Conflict {
+    name: String,
+    requirements: Vec<(String, Requirement)>,
+    rejected: Vec<String>,
+}
+pub struct Resolver {
+    roots: Vec<Requirement>,
+    candidates: BTreeMap<String, Vec<Candidate>>,
+}
+
+const ROOT: &str = "mops.toml";
+
+impl Constraint {
+    pub fn from_spec(name: &str, spec: &str) -> Self {
+        match VersionReq::parse(spec) {
+            Ok(req) => Constraint::Range(req),
+            // Not a semver range, we can only match the exact version
+            Err(_) => Constraint::Source(format!("{name}-{spec}")),
+        }
+    }
+}
+impl Requirement {
+    fn matches(&self, cand: &Candidate) -> bool {
+        match &self.constraint {
+            Constraint::Range(req) => cand.version.as_ref().is_some_and(|v| req.matches(v)),
+            Constraint::Source(key) => &cand.key == key,
+        }
+    }
+}
+impl Resolver {
+    pub fn new(roots: Vec<Requirement>, candidates: Vec<(String, Candidate)>) -> Self {
+        let mut map: BTreeMap<String, Vec<Candidate>> = BTreeMap::new();
+        for (name, cand) in candidates {
+            map.entry(name).or_default().push(cand);
+        }
+        // Try the highest version first. Packages without a version come last.
+        for cands in map.values_mut() {
+            cands.sort_by(|a, b| b.version.cmp(&a.version).then_with(|| a.key.cmp(&b.key)));
+        }
+        Self {
+            roots,
+            candidates: map,
+        }
+    }
+    pub fn resolve(&self) -> Result<Resolution> {
+        let mut selected = BTreeMap::new();
+        let mut conflict = None;
+        if !self.solve(&mut selected, &mut conflict) {
+            let conflict = conflict.expect("resolution failed without a conflict");
+            return Err(anyhow!(self.explain(&conflict)));
+        }
+        let mut overridden = Vec::new();
+        for (name, idx) in &selected {
+            let cand = &self.candidates[name][*idx];
+            for (requester, req) in self.all_requirements_on(name, &selected) {
+                if !req.matches(cand) {
+                    overridden.push((requester, req.clone(), cand.label.clone()));
+                }
+            }
+        }
+        let selected = selected
+            .into_iter()
+            .map(|(name, idx)| {
+                let key = self.candidates[&name][idx].key.clone();
+                (name, key)
+            })
+            .collect();
+        Ok(Resolution {
+            selected,
+            overridden,
+        })
+    }
+    fn solve(
+        &self,
+        selected: &mut BTreeMap<String, usize>,
+        conflict: &mut Option<Conflict>,
+    ) -> bool {
+        let Some(name) = self.next_unselected(selected) else {
+            return true;
+        };
+        let requirements = self.requirements_on(&name, selected);
+        let mut rejected = Vec::new();
+        let cands = self
+            .candidates
+            .get(&name)
+            .map(|c| c.as_slice())
+            .unwrap_or(&[]);
+        for (idx, cand) in cands.iter().enumerate() {
+            if !requirements.iter().all(|(_, req)| req.matches(cand)) {
+                continue;
+            }
+            // The new candidate must not contradict the packages we already picked
+            if let Some(dep) = cand.dependencies.iter().find(|dep| {
+                !self.is_pinned(&dep.name)
+                    && selected
+                        .get(&dep.name)
+                        .is_some_and(|i| !dep.matches(&self.candidates[&dep.name][*i]))
+            }) {
+                let picked = &self.candidates[&dep.name][selected[&dep.name]];
+                rejected.push(format!(
+                    "{} requires {} = \"{}\", but {} is already selected",
+                    cand.label, dep.name, dep.spec, picked.label
+                ));
+                continue;
+            }
+            selected.insert(name.clone(), idx);
+            if self.solve(selected, conflict) {
+                return true;
+            }
+            selected.remove(&name);
+        }
+        if conflict.is_none() {
+            *conflict = Some(Conflict {
+                name,
+                requirements: requirements
+                    .into_iter()
+                    .map(|(r, req)| (r, req.clone()))
+                    .collect(),
+                rejected,
+            });
+        }
+        false
+    }
+    fn is_pinned(&self, name: &str) -> bool {
+        self.roots.iter().any(|r| r.name == name)
+    }
+    fn next_unselected(&self, selected: &BTreeMap<String, usize>) -> Option<String> {
+        let root = self.roots.iter();
+        let deps = selected
+            .iter()
+            .flat_map(|(name, idx)| self.candidates[name][*idx].dependencies.iter());
+        root.chain(deps)
+            .find(|r| !selected.contains_key(&r.name))
+            .map(|r| r.name.clone())
+    }
+    /// Requirements from mops.toml and all selected packages. Used for reporting.
+    fn all_requirements_on(
+        &self,
+        name: &str,
+        selected: &BTreeMap<String, usize>,
+    ) -> Vec<(String, &Requirement)> {
+        let mut res: Vec<_> = self
+            .roots
+            .iter()
+            .filter(|r| r.name == name)
+            .map(|r| (ROOT.to_string(), r))
+            .collect();
+        for (n, idx) in selected {
+            let cand = &self.candidates[n][*idx];
+            for dep in cand.dependencies.iter().filter(|d| d.name == name) {
+                res.push((cand.label.clone(), dep));
+            }
+        }
+        res
+    }
+    /// Requirements that the selected version has to satisfy. mops.toml overrides transitive requirements.
+    fn requirements_on(
+        &self,
+        name: &str,
+        selected: &BTreeMap<String, usize>,
+    ) -> Vec<(String, &Requirement)> {
+        let all = self.all_requirements_on(name, selected);
+        if self.is_pinned(name) {
+            all.into_iter().filter(|(r, _)| r == ROOT).collect()
+        } else {
+            all
+        }
+    }
+    fn explain(&self, conflict: &Conflict) -> String {
+        let name = &conflict.name;
+        let mut msg = format!(
+            "Cannot find a version of {} that satisfies all requirements:\n",
+            style(name).bold()
+        );
+        for (requester, req) in &conflict.requirements {
+            msg.push_str(&format!(
+                "  {} requires {name} = \"{}\"\n",
+                style(requester).green(),
+                req.spec
+            ));
+        }
+        for r in &conflict.rejected {
+            msg.push_str(&format!("  {r}\n"));
+        }
+        let available: Vec<_> = self
+            .candidates
+            .get(name)
+            .map(|c| c.iter().map(|c| c.label.as_str()).collect())
+            .unwrap_or_default();
+        if available.is_empty() {
+            msg.push_str(&format!("No package named {name} is found"));
+        } else {
+            msg.push_str(&format!("Available: {}", available.join(", ")));
+        }
+        if !self.is_pinned(name) {
+            msg.push_str(&format!(
+                "\nYou can pin the version of {name} in mops.toml to override the transitive requirements."
+            ));
+        }
+        msg
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn req(name: &str, spec: &str) -> Requirement {
+        Requirement {
+            name: name.to_string(),
+            spec: spec.to_string(),
+            constraint: Constraint::from_spec(name, spec),
+        }
+    }
+    fn cand(name: &str, version: &str, deps: &[(&str, &str)]) -> (String, Candidate) {
+        let cand = Candidate {
+            key: format!("{name}-{version}"),
+            label: format!("{name}@{version}"),
+            version: Some(version.parse().unwrap()),
+            dependencies: deps.iter().map(|(n, s)| req(n, s)).collect(),
+        };
+        (name.to_string(), cand)
+    }
+    fn base_versions() -> Vec<(String, Candidate)> {
+        ["0.10.0", "0.11.0", "0.11.2", "0.11.5", "0.12.0"]
+            .iter()
+            .map(|v| cand("base", v, &[]))
+            .collect()
+    }
+    fn resolve(roots: &[(&str, &str)], candidates: Vec<(String, Candidate)>) -> Result<Resolution> {
+        let roots = roots.iter().map(|(n, s)| req(n, s)).collect();
+        Resolver::new(roots, candidates).resolve()
+    }
+
+    #[test]
+    fn ranges() {
+        let select = |spec| {
+            resolve(&[("base", spec)], base_versions())
+                .unwrap()
+                .selected["base"]
+                .clone()
+        };
+        assert_eq!(select("0.11.0"), "base-0.11.5");
+        assert_eq!(select("^0.11.2"), "base-0.11.5");
+        assert_eq!(select("~0.11.0"), "base-0.11.5");
+        assert_eq!(select("~0.10"), "base-0.10.0");
+        assert_eq!(select("=0.11.2"), "base-0.11.2");
+        assert_eq!(select(">=0.11.0, <0.11.5"), "base-0.11.2");
+        assert_eq!(select("*"), "base-0.12.0");
+    }
+    #[test]
+    fn deduplicate_transitive_bump() {
+        let mut candidates = base_versions();
+        candidates.retain(|(_, c)| c.key != "base-0.11.5");
+        candidates.push(cand("lib", "1.0.0", &[("base", "0.11.2")]));
+        let res = resolve(&[("base", "0.11.0"), ("lib", "1.0.0")], candidates).unwrap();
+        assert_eq!(res.selected["base"], "base-0.11.2");
+        assert_eq!(res.selected["lib"], "lib-1.0.0");
+        assert!(res.overridden.is_empty());
+    }
+    #[test]
+    fn root_pin_overrides_transitive_requirement() {
+        let mut candidates = base_versions();
+        candidates.push(cand("lib", "1.0.0", &[("base", "0.12.0")]));
+        let res = resolve(&[("base", "=0.11.0"), ("lib", "1.0.0")], candidates).unwrap();
+        assert_eq!(res.selected["base"], "base-0.11.0");
+        assert_eq!(res.overridden.len(), 1);
+        let (requester, req, selected) = &res.overridden[0];
+        assert_eq!(requester, "lib@1.0.0");
+        assert_eq!(req.spec, "0.12.0");
+        assert_eq!(selected, "base@0.11.0");
+    }
+    #[test]
+    fn unsatisfiable_conflict() {
+        console::set_colors_enabled(false);
+        let mut candidates = base_versions();
+        candidates.push(cand("a", "1.0.0", &[("base", "0.10.0")]));
+        candidates.push(cand("b", "1.0.0", &[("base", "0.12.0")]));
+        let err = resolve(&[("a", "1.0.0"), ("b", "1.0.0")], candidates)
+            .err()
+            .unwrap()
+            .to_string();
+        assert_eq!(
+            err,
+            "Cannot find a version of base that satisfies all requirements:
+  a@1.0.0 requires base = \"0.10.0\"
+  b@1.0.0 requires base = \"0.12.0\"
+Available: base@0.12.0, base@0.11.5, base@0.11.2, base@0.11.0, base@0.10.0
+You can pin the version of base in mops.toml to override the transitive requirements."
+        );
+    }
+    #[test]
+    fn missing_package() {
+        console::set_colors_enabled(false);
+        let err = resolve(&[("nope", "1.0.0")], base_versions())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.ends_with("No package named nope is found"), "{err}");
+    }
+}
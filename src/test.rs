@@ -0,0 +1,155 @@
+use crate::build::get_imports;
+use crate::env::Env;
+use crate::toml::{download_packages_from_lock, generate_moc_args, update_mops_toml};
+use crate::utils::{create_bar, emit, println, Event};
+use anyhow::{anyhow, Context, Result};
+use console::style;
+use ic_agent::Agent;
+use indicatif::HumanDuration;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Instant;
+
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq, Eq)]
+pub enum TestMode {
+    /// Run tests with the Motoko interpreter (moc -r)
+    Interpreter,
+    /// Compile tests with -wasi-system-api and run them with a wasm runtime
+    Wasi,
+}
+
+pub async fn test(agent: &Agent, env: &Env, args: crate::TestArg) -> Result<()> {
+    let start = Instant::now();
+    let mut files = Vec::new();
+    find_test_files(&env.get_test_path(), &mut files)?;
+    if let Some(filter) = &args.filter {
+        files.retain(|f| f.to_string_lossy().contains(filter.as_str()));
+    }
+    if files.is_empty() {
+        return Err(anyhow!(
+            "No test files found. Test files are test/**/*.test.mo in the project root."
+        ));
+    }
+    if !crate::env::is_offline() && !args.lock {
+        let mains: Vec<_> = files.iter().map(|f| f.as_path()).collect();
+        let imports = get_imports(&mains, env, false).await?;
+        update_mops_toml(agent, env, imports.into_iter().flatten().collect()).await?;
+    }
+    download_packages_from_lock(agent, env).await?;
+    let pkgs = generate_moc_args(env)?;
+    let bar = create_bar(files.len());
+    bar.set_prefix("Testing");
+    let mut failed = Vec::new();
+    for file in &files {
+        let display = file.strip_prefix(&env.project_root).unwrap_or(file);
+        bar.set_message(display.display().to_string());
+        let output = match args.mode {
+            TestMode::Interpreter => {
+                let mut moc = env.binary["moc"].get_cmd();
+                moc.arg("-r").arg(file).args(&pkgs);
+                run(moc)?
+            }
+            TestMode::Wasi => {
+                let wasm = env
+                    .get_target_path()
+                    .join("test")
+                    .join(display.with_extension("wasm"));
+                fs::create_dir_all(wasm.parent().unwrap())
+                    .context("Failed to create output directory.")?;
+                let mut moc = env.binary["moc"].get_cmd();
+                moc.arg("-wasi-system-api")
+                    .arg(file)
+                    .args(&pkgs)
+                    .arg("-o")
+                    .arg(&wasm);
+                match run(moc)? {
+                    Ok(_) => {
+                        let mut runtime = Command::new(&args.runtime);
+                        runtime.arg(&wasm);
+                        run(runtime)?
+                    }
+                    err => err,
+                }
+            }
+        };
+        let (success, text) = match &output {
+            Ok(text) => (true, text),
+            Err(text) => (false, text),
+        };
+        emit(Event::TestFinished {
+            file: display,
+            success,
+            output: text,
+        });
+        match output {
+            Ok(_) => println(
+                Some(&bar),
+                "stdout",
+                &format!("{:>12} {}", style("PASS").green().bold(), display.display()),
+            ),
+            Err(output) => {
+                println(
+                    Some(&bar),
+                    "stdout",
+                    &format!("{:>12} {}", style("FAIL").red().bold(), display.display()),
+                );
+                println(Some(&bar), "stderr", &output);
+                failed.push(display.display().to_string());
+            }
+        }
+        bar.inc(1);
+    }
+    bar.finish_and_clear();
+    let passed = files.len() - failed.len();
+    let msg = format!(
+        "{:>12} {passed} passed; {} failed in {}",
+        if failed.is_empty() {
+            style("Tested").green().bold()
+        } else {
+            style("Tested").red().bold()
+        },
+        failed.len(),
+        HumanDuration(start.elapsed())
+    );
+    println(None, "stdout", &msg);
+    if !failed.is_empty() {
+        return Err(anyhow!(
+            "The following tests failed:\n{}",
+            failed.join("\n")
+        ));
+    }
+    Ok(())
+}
+
+/// Returns the combined stdout and stderr of the command. Err when the command exits with non-zero code.
+fn run(mut cmd: Command) -> Result<Result<String, String>> {
+    let output = cmd
+        .output()
+        .with_context(|| format!("Error executing {:#?}", cmd))?;
+    let mut res = String::from_utf8_lossy(&output.stdout).to_string();
+    res.push_str(&String::from_utf8_lossy(&output.stderr));
+    Ok(if output.status.success() {
+        Ok(res)
+    } else {
+        Err(res)
+    })
+}
+
+fn find_test_files(dir: &Path, result: &mut Vec<PathBuf>) -> Result<()> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    let mut entries = fs::read_dir(dir)?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            find_test_files(&path, result)?;
+        } else if path.to_string_lossy().ends_with(".test.mo") {
+            result.push(path);
+        }
+    }
+    Ok(())
+}
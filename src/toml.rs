@@ -1,46 +1,55 @@
 use crate::build::MotokoImport;
 use crate::github::{
-    download_github_package, fetch_file, get_latest_commit, get_latest_tag, parse_github_url,
-    RepoInfo,
+    download_github_package, fetch_file, get_latest_commit, get_latest_tag, guess_version_from_tag,
+    parse_github_url, RepoInfo,
 };
+use crate::registry::{get_registry, Registry};
+use crate::resolver::{Candidate, Constraint, Requirement, Resolver};
 use crate::{
-    env::Env,
-    mops, storage,
-    utils::{create_bar, println},
+    env::{check_online, Env},
+    utils::{create_bar, diff_lines, emit, hash_dir, println, to_hex, Event},
 };
-use anyhow::{anyhow, Error, Result};
+use anyhow::{anyhow, Context, Result};
 use candid::Principal;
+use candid_parser::utils::CandidSource;
 use console::style;
 use futures::future::try_join_all;
 use ic_agent::Agent;
 use indicatif::ProgressBar;
-use semver::Version;
+use semver::{Version, VersionReq};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use toml_edit::{value, DocumentMut, ImDocument};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct Package {
     name: String,
     version: Option<String>,
     source: String,
     base_dir: String,
     repo: Option<RepoInfo>,
-    dependencies: Vec<String>,
+    /// SHA-256 of the files in base_dir, recorded on first download
+    checksum: Option<String>,
+    /// Dependencies as written in the package's mops.toml
+    dependencies: BTreeMap<String, String>,
 }
 #[derive(Debug, Serialize, Deserialize)]
 struct Canister {
     canister_id: Option<String>,
     name: Option<String>,
+    /// Set when mops.toml has a canister_id for each network
+    network: Option<String>,
     timestamp: Option<String>,
     output: Option<String>,
     candid: String,
 }
 #[derive(Default, Serialize, Deserialize)]
 struct Packages {
+    #[serde(default)]
     package: Vec<Package>,
     canister: Option<Vec<Canister>>,
 }
@@ -51,16 +60,8 @@ pub async fn update_mops_toml(
     libs: BTreeSet<MotokoImport>,
 ) -> Result<()> {
     let mops = &env.get_mops_toml_path();
-    let mut doc = if mops.exists() {
-        let str = fs::read_to_string(mops)?;
-        str.parse::<DocumentMut>()?
-    } else {
-        DocumentMut::new()
-    };
-    let service = mops::Service(mops::CANISTER_ID, agent);
-    if doc.get("dependencies").is_none() {
-        doc["dependencies"] = toml_edit::table();
-    }
+    let mut doc = read_mops_toml(env)?;
+    let registry = get_registry(env, agent);
     if doc.get("canister").is_none() {
         doc["canister"] = toml_edit::array();
     }
@@ -88,9 +89,8 @@ pub async fn update_mops_toml(
                 if doc["dependencies"].get(&lib).is_some() {
                     continue;
                 }
-                let version = service.get_highest_version(&lib).await?.into_result();
-                match version {
-                    Ok(version) => {
+                match registry.get_highest_version(&lib).await? {
+                    Some(version) => {
                         println(
                             None,
                             "stdout",
@@ -101,7 +101,7 @@ pub async fn update_mops_toml(
                         );
                         doc["dependencies"][lib] = value(version);
                     }
-                    Err(_) => unknown_libs.push(lib),
+                    None => unknown_libs.push(lib),
                 }
             }
             MotokoImport::Canister(name) => {
@@ -118,8 +118,11 @@ pub async fn update_mops_toml(
                 let canisters = doc["canister"].as_array_of_tables_mut().unwrap();
                 let id = id.to_string();
                 if canisters.iter().any(|c| {
-                    c.get("canister_id")
-                        .is_some_and(|n| n.as_value().unwrap().as_str().unwrap() == id)
+                    c.get("canister_id").is_some_and(|n| {
+                        n.as_str() == Some(&id)
+                            || n.as_table_like()
+                                .is_some_and(|t| t.iter().any(|(_, v)| v.as_str() == Some(&id)))
+                    })
                 }) {
                     continue;
                 }
@@ -145,10 +148,134 @@ pub async fn update_mops_toml(
     update_mops_lock(agent, env).await?;
     Ok(())
 }
+pub async fn add_dependency(agent: &Agent, env: &Env, args: crate::AddArg) -> Result<()> {
+    let mut doc = read_mops_toml(env)?;
+    let (name, version) = match args.name.split_once('@') {
+        Some((name, version)) => (name.to_string(), Some(version.to_string())),
+        None => (args.name.clone(), None),
+    };
+    if version.is_some() && (args.git.is_some() || args.path.is_some()) {
+        return Err(anyhow!(
+            "Cannot use a version in {} with --git or --path",
+            args.name
+        ));
+    }
+    let spec = if let Some(url) = args.git {
+        if !url.starts_with("https://github.com") {
+            return Err(anyhow!("Only github repositories are supported: {url}"));
+        }
+        url
+    } else if let Some(path) = args.path {
+        if !path.join("mops.toml").exists() && !path.join("src").exists() {
+            return Err(anyhow!("{} is not a Motoko package", path.display()));
+        }
+        path.to_string_lossy().to_string()
+    } else if let Some(version) = version {
+        // Check that the version exists before writing it to mops.toml
+        let registry = get_registry(env, agent);
+        let ver = resolve_fetch_version(registry.as_ref(), &name, &version).await?;
+        registry
+            .get_package_details(&name, &ver)
+            .await
+            .with_context(|| format!("Cannot find {name}@{ver} on mops"))?;
+        version
+    } else {
+        get_registry(env, agent)
+            .get_highest_version(&name)
+            .await?
+            .ok_or_else(|| anyhow!("Cannot find {name} on mops"))?
+    };
+    let action = if doc["dependencies"].get(&name).is_some() {
+        "Updated"
+    } else {
+        "Added"
+    };
+    doc["dependencies"][&name] = value(&spec);
+    let toml_path = env.get_mops_toml_path();
+    let lock_path = env.get_mops_lock_path();
+    let old_toml = fs::read(&toml_path).ok();
+    let old_lock = fs::read(&lock_path).ok();
+    fs::write(&toml_path, doc.to_string())?;
+    println(
+        None,
+        "stdout",
+        &format!(
+            "{:>12} {name} = \"{spec}\" to mops.toml",
+            style(action).green().bold()
+        ),
+    );
+    let res = async {
+        update_mops_lock(agent, env).await?;
+        download_packages_from_lock(agent, env).await
+    }
+    .await;
+    if let Err(e) = res {
+        // Do not leave an entry that cannot be resolved in mops.toml
+        for (path, old) in [(&toml_path, old_toml), (&lock_path, old_lock)] {
+            match old {
+                Some(old) => fs::write(path, old)?,
+                None if path.exists() => fs::remove_file(path)?,
+                None => (),
+            }
+        }
+        return Err(e.context(format!(
+            "Failed to add {name}. mops.toml and mops.lock are restored."
+        )));
+    }
+    Ok(())
+}
+pub async fn remove_dependency(agent: &Agent, env: &Env, args: crate::RemoveArg) -> Result<()> {
+    let mut doc = read_mops_toml(env)?;
+    let deps = doc["dependencies"].as_table_like_mut().unwrap();
+    if deps.remove(&args.name).is_none() {
+        return Err(anyhow!("{} is not a dependency in mops.toml", args.name));
+    }
+    fs::write(env.get_mops_toml_path(), doc.to_string())?;
+    println(
+        None,
+        "stdout",
+        &format!(
+            "{:>12} {} from mops.toml",
+            style("Removed").green().bold(),
+            args.name
+        ),
+    );
+    update_mops_lock(agent, env).await?;
+    Ok(())
+}
+fn read_mops_toml(env: &Env) -> Result<DocumentMut> {
+    let mops = env.get_mops_toml_path();
+    let mut doc = if mops.exists() {
+        let str = fs::read_to_string(mops)?;
+        str.parse::<DocumentMut>()?
+    } else {
+        DocumentMut::new()
+    };
+    if doc.get("dependencies").is_none() {
+        doc["dependencies"] = toml_edit::table();
+    }
+    Ok(doc)
+}
 async fn update_mops_lock(agent: &Agent, env: &Env) -> Result<()> {
     let lock = env.get_mops_lock_path();
-    let pkgs = parse_mops_lock(&lock).unwrap_or_default();
+    // mops.lock is regenerated here, so an old or broken lock only loses the cached resolution
+    let pkgs = read_mops_lock(&lock).unwrap_or_else(|e| {
+        println(
+            None,
+            "stderr",
+            &format!(
+                "{:>12} {}. Regenerating mops.lock.",
+                style("[Warning]").yellow().bold(),
+                e.to_string().lines().next().unwrap_or_default()
+            ),
+        );
+        Packages::default()
+    });
     let mut map: BTreeMap<_, _> = pkgs.package.into_iter().map(|p| (p.get_key(), p)).collect();
+    let old_pkgs: Vec<_> = map
+        .values()
+        .map(|p| (p.name.clone(), p.get_label()))
+        .collect();
     let mut canisters: BTreeMap<_, _> = pkgs
         .canister
         .unwrap_or_default()
@@ -156,10 +283,31 @@ async fn update_mops_lock(agent: &Agent, env: &Env) -> Result<()> {
         .map(|c| (c.get_key(), c))
         .collect();
     let str = fs::read_to_string(env.get_mops_toml_path())?;
-    let toml = parse_mops_toml(&env.project_root, &str)?;
-    let service = mops::Service(mops::CANISTER_ID, agent);
+    let toml = parse_mops_toml(&env.project_root, &str, &env.network.name)?;
+    let registry = get_registry(env, agent);
     let bar = create_bar(toml.dependencies.len() + toml.canisters.len());
     bar.set_prefix("Updating mops.lock");
+    // Remove canisters that are no longer in mops.toml. Entries of other networks are kept.
+    let current: BTreeSet<_> = toml.canisters.iter().map(|c| c.get_key()).collect();
+    let per_network: BTreeSet<_> = toml
+        .canisters
+        .iter()
+        .filter(|c| c.network.is_some())
+        .filter_map(|c| c.name.clone())
+        .collect();
+    canisters.retain(|key, c| {
+        let other_network = c.network.as_ref().is_some_and(|n| *n != env.network.name)
+            && c.name.as_ref().is_some_and(|n| per_network.contains(n));
+        let keep = current.contains(key) || other_network;
+        if !keep {
+            println(
+                Some(&bar),
+                "stdout",
+                &format!("{:>12} canister {key}", style("Removed").green().bold()),
+            );
+        }
+        keep
+    });
     for canister in toml.canisters {
         if let Some(c) = canisters.get(&canister.get_key()) {
             if c.no_need_to_update(&canister) {
@@ -170,28 +318,13 @@ async fn update_mops_lock(agent: &Agent, env: &Env) -> Result<()> {
         let (timestamp, candid) = if let Some(candid) = canister.candid {
             (None, candid)
         } else {
-            use std::time::SystemTime;
-            // TODO handle aaaaa-aa
-            let id = Principal::from_text(canister.canister_id.clone().unwrap())?;
-            let candid = String::from_utf8(
-                agent
-                    .read_state_canister_metadata(id, "candid:service")
-                    .await?,
-            )?;
-            let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
-            println(
-                Some(&bar),
-                "stdout",
-                &format!(
-                    "{:>12} canister interface for {id}",
-                    style("Fetched").green().bold()
-                ),
-            );
-            (Some(format!("{:?}", timestamp)), candid)
+            let id = canister.canister_id.as_ref().unwrap();
+            fetch_candid(agent, env, id, &bar).await?
         };
         let info = Canister {
             canister_id: canister.canister_id,
             name: canister.name,
+            network: canister.network,
             output: canister.output,
             timestamp,
             candid,
@@ -200,140 +333,274 @@ async fn update_mops_lock(agent: &Agent, env: &Env) -> Result<()> {
         bar.inc(1);
     }
 
-    let mut queue = toml.dependencies.into_iter().collect::<VecDeque<_>>();
-    // TODO: maintain a map between mops to resolved package.get_key, so we can rewrite dependencies entry at the end
+    let roots = toml.dependencies;
+    let mut queue = roots.iter().cloned().collect::<VecDeque<_>>();
+    // Map from the display key of a dependency to the key of the fetched package
+    let mut resolved: BTreeMap<String, String> = BTreeMap::new();
+    let mut collected: BTreeMap<String, Package> = BTreeMap::new();
     while let Some(m) = queue.pop_front() {
+        let display_key = m.get_display_key();
+        if resolved.contains_key(&display_key) {
+            bar.inc(1);
+            continue;
+        }
+        bar.set_message(m.get_name().to_string());
         let pkg = match m {
             Mops::Mops { name, version } => {
-                bar.set_message(name.clone());
-                if map.contains_key(&format!("{name}-{version}")) {
-                    bar.inc(1);
-                    continue;
-                }
-                let pkg = service
-                    .get_package_details(&name, &version)
-                    .await?
-                    .into_result()
-                    .map_err(Error::msg)?;
-                let source = pkg.publication.storage.to_string();
-                let base_dir = pkg.config.base_dir;
-                let dependencies = pkg
-                    .config
-                    .dependencies
-                    .into_iter()
-                    .map(|d| {
-                        let name = d.name;
-                        let mops = if d.version.is_empty() {
-                            Mops::Repo { name, repo: d.repo }
-                        } else {
-                            Mops::Mops {
-                                name,
-                                version: d.version,
-                            }
-                        };
-                        bar.inc_length(1);
-                        let key = mops.get_display_key();
-                        queue.push_back(mops);
-                        key
-                    })
-                    .collect();
-                Package {
-                    name,
-                    version: Some(version),
-                    source,
-                    base_dir,
-                    repo: None,
-                    dependencies,
+                let version = resolve_fetch_version(registry.as_ref(), &name, &version).await?;
+                if let Some(pkg) = take_package(&mut map, &collected, &format!("{name}-{version}"))
+                {
+                    pkg
+                } else {
+                    let info = registry.get_package_details(&name, &version).await?;
+                    Package {
+                        name,
+                        version: Some(version),
+                        source: info.source,
+                        base_dir: info.base_dir,
+                        repo: None,
+                        checksum: None,
+                        dependencies: info.dependencies,
+                    }
                 }
             }
             Mops::Repo { name, repo } => {
-                bar.set_message(name.clone());
                 let repo_info = parse_github_url(&repo).await?;
-                if map.contains_key(&format!("{}-{}-{}", name, repo_info.repo, repo_info.commit)) {
-                    bar.inc(1);
-                    continue;
-                }
-                let mut version = None;
-                let dependencies = if let Ok(str) = fetch_file(&repo_info, "mops.toml").await {
-                    // I hope the base_path here is irrelevant, so we can just use cwd
-                    let mops = parse_mops_toml(Path::new("."), &str)?;
-                    version = mops.version;
-                    // TODO remove Mops::Local
-                    mops.dependencies
-                        .into_iter()
-                        .map(|m| {
-                            let key = m.get_display_key();
-                            bar.inc_length(1);
-                            queue.push_back(m);
-                            key
-                        })
-                        .collect()
+                let key = format!("{}-{}-{}", name, repo_info.repo, repo_info.commit);
+                if let Some(pkg) = take_package(&mut map, &collected, &key) {
+                    pkg
                 } else {
-                    Vec::new()
-                };
-                if version.is_none() {
-                    version = repo_info.guess_version();
-                }
-                Package {
-                    name,
-                    version,
-                    source: "github".to_string(),
-                    base_dir: repo_info.base_dir.clone(),
-                    repo: Some(repo_info),
-                    dependencies,
+                    let mut version = None;
+                    let dependencies = if let Ok(str) = fetch_file(&repo_info, "mops.toml").await {
+                        // I hope the base_path here is irrelevant, so we can just use cwd
+                        let mops = parse_mops_toml(Path::new("."), &str, &env.network.name)?;
+                        version = mops.version;
+                        // TODO remove Mops::Local
+                        mops.dependencies.iter().map(|m| m.get_entry()).collect()
+                    } else {
+                        BTreeMap::new()
+                    };
+                    if version.is_none() {
+                        version = repo_info.guess_version();
+                    }
+                    Package {
+                        name,
+                        version,
+                        source: "github".to_string(),
+                        base_dir: repo_info.base_dir.clone(),
+                        repo: Some(repo_info),
+                        checksum: None,
+                        dependencies,
+                    }
                 }
             }
             Mops::Local { name, path } => {
-                bar.set_message(name.clone());
                 let toml = Path::new(&path).join("mops.toml");
                 let canonicalized = fs::canonicalize(path)?;
-                if map.contains_key(&format!("{name}-{}", canonicalized.display())) {
-                    bar.inc(1);
-                    continue;
-                }
-                let source = format!("file://{}", canonicalized.display());
-                let mut version = None;
-                let mops = if toml.exists() {
-                    let str = fs::read_to_string(toml)?;
-                    let mops = parse_mops_toml(&canonicalized, &str)?;
-                    version = mops.version;
-                    mops.dependencies
+                let key = format!("{name}-{}", canonicalized.display());
+                if let Some(pkg) = take_package(&mut map, &collected, &key) {
+                    pkg
                 } else {
-                    Vec::new()
-                };
-                Package {
-                    name,
-                    version,
-                    source,
-                    base_dir: "src".to_string(),
-                    repo: None,
-                    dependencies: mops
-                        .into_iter()
-                        .map(|m| {
-                            let key = m.get_display_key();
-                            bar.inc_length(1);
-                            queue.push_back(m);
-                            key
-                        })
-                        .collect(),
+                    let source = format!("file://{}", canonicalized.display());
+                    let mut version = None;
+                    let dependencies = if toml.exists() {
+                        let str = fs::read_to_string(toml)?;
+                        let mops = parse_mops_toml(&canonicalized, &str, &env.network.name)?;
+                        version = mops.version;
+                        mops.dependencies.iter().map(|m| m.get_entry()).collect()
+                    } else {
+                        BTreeMap::new()
+                    };
+                    Package {
+                        name,
+                        version,
+                        source,
+                        base_dir: "src".to_string(),
+                        repo: None,
+                        checksum: None,
+                        dependencies,
+                    }
                 }
             }
         };
-        assert!(map.insert(pkg.get_key(), pkg).is_none());
+        for (name, spec) in &pkg.dependencies {
+            bar.inc_length(1);
+            queue.push_back(parse_dependency(name, spec));
+        }
+        let key = pkg.get_key();
+        resolved.insert(display_key, key.clone());
+        collected.insert(key, pkg);
         bar.inc(1);
     }
     bar.finish_and_clear();
-    let pkgs = resolve_versions(map)?;
+    // Only packages reachable from mops.toml are kept in mops.lock
+    let pkgs = resolve_versions(&roots, collected, &resolved)?;
+    for p in &pkgs {
+        emit(Event::PackageResolved {
+            name: &p.name,
+            version: p.version.as_deref(),
+            source: &p.source,
+        });
+    }
+    // Packages that are upgraded or downgraded are not removed
+    let new_names: BTreeSet<_> = pkgs.iter().map(|p| p.name.as_str()).collect();
+    for (name, label) in old_pkgs {
+        if !new_names.contains(name.as_str()) {
+            println(
+                None,
+                "stdout",
+                &format!("{:>12} {label}", style("Removed").green().bold()),
+            );
+        }
+    }
+    let pkgs = Packages {
+        package: pkgs,
+        canister: Some(canisters.into_values().collect()),
+    };
+    write_mops_lock(&lock, &pkgs)
+}
+/// The interface of the management canister aaaaa-aa
+const MANAGEMENT_CANISTER_DID: &str = include_str!("ic.did");
+/// Returns the timestamp and the candid:service metadata of the canister
+async fn fetch_candid(
+    agent: &Agent,
+    env: &Env,
+    id: &str,
+    bar: &ProgressBar,
+) -> Result<(Option<String>, String)> {
+    use std::time::SystemTime;
+    let id = Principal::from_text(id)?;
+    let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+    if id == Principal::management_canister() {
+        // The management canister has no candid:service metadata. Use the interface bundled with mops-cli.
+        println(
+            Some(bar),
+            "stdout",
+            &format!(
+                "{:>12} bundled interface for the management canister {id}",
+                style("Using").green().bold()
+            ),
+        );
+        return Ok((
+            Some(format!("{:?}", timestamp)),
+            MANAGEMENT_CANISTER_DID.to_string(),
+        ));
+    }
+    check_online(&format!("fetch canister interface for {id}"))?;
+    env.network.connect(agent).await?;
+    let candid = String::from_utf8(
+        agent
+            .read_state_canister_metadata(id, "candid:service")
+            .await?,
+    )?;
+    println(
+        Some(bar),
+        "stdout",
+        &format!(
+            "{:>12} canister interface for {id} from {}",
+            style("Fetched").green().bold(),
+            env.network.name
+        ),
+    );
+    Ok((Some(format!("{:?}", timestamp)), candid))
+}
+/// Fetch the candid interface of remote canisters again, and check that the new interface is compatible with mops.lock
+pub async fn refresh_canisters(agent: &Agent, env: &Env, name: Option<String>) -> Result<()> {
+    use candid_parser::utils::service_compatible;
+    let lock = env.get_mops_lock_path();
+    let mut lock_file = read_mops_lock(&lock)?;
+    let mut canisters: Vec<_> = lock_file
+        .canister
+        .iter_mut()
+        .flatten()
+        .filter(|c| c.network.as_ref().is_none_or(|n| *n == env.network.name))
+        .filter(|c| {
+            name.as_ref().is_none_or(|name| {
+                c.name.as_ref() == Some(name) || c.canister_id.as_ref() == Some(name)
+            })
+        })
+        .collect();
+    if let Some(name) = &name {
+        match canisters.first() {
+            None => return Err(anyhow!("Canister {name} not found in mops.lock")),
+            Some(c) if c.timestamp.is_none() => {
+                return Err(anyhow!(
+                    "The interface of canister {name} is read from a local candid file"
+                ))
+            }
+            _ => (),
+        }
+    }
+    canisters.retain(|c| c.timestamp.is_some());
+    let bar = ProgressBar::hidden();
+    let mut incompatible = Vec::new();
+    for c in canisters {
+        let id = c.canister_id.clone().unwrap();
+        let label = c.name.clone().unwrap_or(id.clone());
+        let (timestamp, candid) = fetch_candid(agent, env, &id, &bar).await?;
+        let old = normalize_candid(&c.candid)?;
+        let new = normalize_candid(&candid)?;
+        if old == new {
+            println(
+                None,
+                "stdout",
+                &format!("{:>12} canister {label}", style("Unchanged").green().bold()),
+            );
+        } else {
+            println(
+                None,
+                "stdout",
+                &format!("{:>12} canister {label}", style("Updated").green().bold()),
+            );
+            println(None, "stdout", &diff_lines(&old, &new));
+            // Code compiled against the old interface keeps working if the new one is a subtype
+            if let Err(e) =
+                service_compatible(CandidSource::Text(&candid), CandidSource::Text(&c.candid))
+            {
+                println(
+                    None,
+                    "stderr",
+                    &format!(
+                        "{:>12} The new interface of canister {label} is not backward compatible with mops.lock. Code compiled against the old interface may fail at runtime.\n{e:#}",
+                        style("[Warning]").red().bold()
+                    ),
+                );
+                incompatible.push(label);
+            }
+        }
+        c.timestamp = timestamp;
+        c.candid = candid;
+    }
+    write_mops_lock(&lock, &lock_file)?;
+    if !incompatible.is_empty() {
+        println(
+            None,
+            "stderr",
+            &format!(
+                "{:>12} Incompatible interface changes in: {}",
+                style("[Warning]").red().bold(),
+                incompatible.join(", ")
+            ),
+        );
+    }
+    Ok(())
+}
+/// Pretty print the candid interface, so that the diff ignores formatting changes
+fn normalize_candid(candid: &str) -> Result<String> {
+    let (env, actor) = CandidSource::Text(candid).load()?;
+    Ok(candid::pretty::candid::compile(&env, &actor))
+}
+fn write_mops_lock(lock: &Path, pkgs: &Packages) -> Result<()> {
     let mut res = DocumentMut::new();
     let mut pkg_array = toml_edit::ArrayOfTables::new();
-    for p in pkgs {
-        let d = toml_edit::ser::to_document(&p)?;
+    for p in &pkgs.package {
+        let d = toml_edit::ser::to_document(p)?;
         pkg_array.push(d.as_table().clone());
     }
     res.insert("package", toml_edit::Item::ArrayOfTables(pkg_array));
     let mut can_array = toml_edit::ArrayOfTables::new();
-    for c in canisters.into_values() {
-        let d = toml_edit::ser::to_document(&c)?;
+    for c in pkgs.canister.iter().flatten() {
+        let d = toml_edit::ser::to_document(c)?;
         can_array.push(d.as_table().clone());
     }
     res.insert("canister", toml_edit::Item::ArrayOfTables(can_array));
@@ -345,41 +612,119 @@ async fn update_mops_lock(agent: &Agent, env: &Env) -> Result<()> {
     buf.write_all(res.to_string().as_bytes())?;
     Ok(())
 }
-fn resolve_versions(map: BTreeMap<String, Package>) -> Result<Vec<Package>> {
-    let mut res: BTreeMap<String, Package> = BTreeMap::new();
-    for pkg in map.into_values() {
-        if let Some(e) = res.get(&pkg.name) {
-            match (&e.version, &pkg.version) {
-                (None, _) | (_, None) => return Err(anyhow!(resolve_error(e, &pkg))),
-                (Some(ve), Some(vp)) => match (parse_version(ve), parse_version(vp)) {
-                    (None, _) | (_, None) => return Err(anyhow!(resolve_error(e, &pkg))),
-                    (Some(ve), Some(vp)) => {
-                        if ve < vp {
-                            res.insert(pkg.name.clone(), pkg);
-                        }
-                    }
-                },
-            }
-        } else {
-            res.insert(pkg.name.clone(), pkg);
+fn resolve_versions(
+    roots: &[Mops],
+    mut collected: BTreeMap<String, Package>,
+    resolved: &BTreeMap<String, String>,
+) -> Result<Vec<Package>> {
+    let to_requirement = |m: &Mops| {
+        let constraint = match m {
+            Mops::Mops { name, version } => Constraint::from_spec(name, version),
+            _ => Constraint::Source(resolved[&m.get_display_key()].clone()),
+        };
+        let (name, spec) = m.get_entry();
+        Requirement {
+            name,
+            spec,
+            constraint,
         }
+    };
+    let roots = roots.iter().map(to_requirement).collect();
+    let candidates = collected
+        .values()
+        .map(|pkg| {
+            let dependencies = pkg
+                .dependencies
+                .iter()
+                .map(|(name, spec)| to_requirement(&parse_dependency(name, spec)))
+                .collect();
+            let cand = Candidate {
+                key: pkg.get_key(),
+                label: pkg.get_label(),
+                version: pkg.version.as_deref().and_then(parse_version),
+                dependencies,
+            };
+            (pkg.name.clone(), cand)
+        })
+        .collect();
+    let resolution = Resolver::new(roots, candidates).resolve()?;
+    for (requester, req, selected) in resolution.overridden {
+        println(
+            None,
+            "stderr",
+            &format!(
+                "{:>12} {requester} requires {} = \"{}\", but mops.toml selects {selected}",
+                style("[Warning]").red().bold(),
+                req.name,
+                req.spec,
+            ),
+        );
     }
-    Ok(res.into_values().collect())
+    Ok(resolution
+        .selected
+        .into_values()
+        .map(|key| collected.remove(&key).unwrap())
+        .collect())
 }
 fn parse_version(ver: &str) -> Option<Version> {
     ver.parse::<Version>().ok()
 }
-fn resolve_error(p1: &Package, p2: &Package) -> String {
-    let p1 = toml_edit::ser::to_string(p1).unwrap();
-    let p2 = toml_edit::ser::to_string(p2).unwrap();
-    format!(
-        "Version conflict:\n{}\nand\n\n{}",
-        style(&p1).green(),
-        style(&p2).green()
-    )
+/// The exact version to download for a dependency, when it is decided by the spec alone.
+/// None means the highest version on mops that satisfies the spec.
+fn get_fetch_version(spec: &str) -> Option<String> {
+    use semver::Op;
+    if parse_version(spec).is_some() {
+        return Some(spec.to_string());
+    }
+    match VersionReq::parse(spec) {
+        Ok(req) => {
+            // Download the lower bound of the range. Newer versions are only picked when requested by other packages.
+            // A partial bound, e.g., ^0.11, may not be published, so it is resolved by the registry.
+            let ver = req.comparators.iter().find_map(|c| match c.op {
+                Op::Caret | Op::Tilde | Op::Exact | Op::GreaterEq => Some(Version {
+                    major: c.major,
+                    minor: c.minor?,
+                    patch: c.patch?,
+                    pre: c.pre.clone(),
+                    build: semver::BuildMetadata::EMPTY,
+                }),
+                _ => None,
+            })?;
+            req.matches(&ver).then(|| ver.to_string())
+        }
+        Err(_) => Some(spec.to_string()),
+    }
+}
+/// The version of a mops package to download for the spec
+async fn resolve_fetch_version(registry: &dyn Registry, name: &str, spec: &str) -> Result<String> {
+    if let Some(ver) = get_fetch_version(spec) {
+        return Ok(ver);
+    }
+    let req = VersionReq::parse(spec)?;
+    if req == VersionReq::STAR {
+        return registry
+            .get_highest_version(name)
+            .await?
+            .ok_or_else(|| anyhow!("Cannot find {name} on mops"));
+    }
+    registry
+        .list_versions(name)
+        .await?
+        .into_iter()
+        .filter(|v| req.matches(v))
+        .max()
+        .map(|v| v.to_string())
+        .ok_or_else(|| anyhow!("Cannot find a version of {name} that satisfies {spec} on mops"))
+}
+fn take_package(
+    map: &mut BTreeMap<String, Package>,
+    collected: &BTreeMap<String, Package>,
+    key: &str,
+) -> Option<Package> {
+    collected.get(key).cloned().or_else(|| map.remove(key))
 }
 pub fn generate_moc_args(env: &Env) -> Result<Vec<String>> {
-    let lock = parse_mops_lock(&env.get_mops_lock_path()).unwrap_or_default();
+    let lock = read_mops_lock(&env.get_mops_lock_path())?;
     let mut args: Vec<_> = lock
         .package
         .into_iter()
@@ -398,27 +743,27 @@ pub fn generate_moc_args(env: &Env) -> Result<Vec<String>> {
         if !canisters.is_empty() {
             args.extend_from_slice(&["--actor-idl".to_string(), idl_path.display().to_string()]);
         }
-        let (with_id, type_only): (Vec<_>, Vec<_>) =
-            canisters.into_iter().partition(|c| c.canister_id.is_some());
+        let (with_id, type_only): (Vec<_>, Vec<_>) = canisters
+            .into_iter()
+            .filter(|c| c.network.as_ref().is_none_or(|n| *n == env.network.name))
+            .partition(|c| c.canister_id.is_some());
         for c in type_only {
-            use candid_parser::{bindings::motoko, utils::CandidSource};
-            let candid = Path::new(&c.candid);
-            let output = PathBuf::from(&c.output.unwrap());
-            let (env, actor) = CandidSource::File(candid).load()?;
-            let binding = motoko::compile(&env, &actor);
-            fs::create_dir_all(output.parent().unwrap())?;
-            fs::write(output, binding)?;
+            let candid = CandidSource::File(Path::new(&c.candid));
+            generate_motoko_binding(candid, &c.output.unwrap())?;
         }
         for c in with_id {
             let canister_id = c.canister_id.unwrap();
             let file = idl_path.join(format!("{}.did", canister_id));
             fs::create_dir_all(file.parent().unwrap())?;
-            if c.timestamp.is_none() {
-                let candid = fs::read_to_string(c.candid)?;
-                fs::write(file, candid)?;
+            let candid = if c.timestamp.is_none() {
+                fs::read_to_string(c.candid)?
             } else {
-                fs::write(file, c.candid)?;
+                c.candid
+            };
+            if let Some(output) = &c.output {
+                generate_motoko_binding(CandidSource::Text(&candid), output)?;
             }
+            fs::write(file, candid)?;
             if let Some(name) = c.name {
                 args.extend_from_slice(&["--actor-alias".to_string(), name, canister_id]);
             }
@@ -426,31 +771,191 @@ pub fn generate_moc_args(env: &Env) -> Result<Vec<String>> {
     }
     Ok(args)
 }
-pub async fn update_packages_from_lock(agent: &Agent, env: &Env) -> Result<()> {
+/// Write the types of the candid interface as a Motoko module
+fn generate_motoko_binding(candid: CandidSource, output: &str) -> Result<()> {
+    use candid_parser::bindings::motoko;
+    let output = Path::new(output);
+    let (env, actor) = candid.load()?;
+    let binding = motoko::compile(&env, &actor);
+    fs::create_dir_all(output.parent().unwrap())?;
+    fs::write(output, binding)?;
+    Ok(())
+}
+pub async fn update_packages_from_lock(
+    agent: &Agent,
+    env: &Env,
+    args: crate::UpdateArg,
+) -> Result<()> {
     let lock = env.get_mops_lock_path();
-    let pkgs = parse_mops_lock(&lock)?.package;
-    let service = Rc::new(mops::Service(mops::CANISTER_ID, agent));
+    let mut pkgs = parse_mops_lock(&lock)?.package;
+    if !args.packages.is_empty() {
+        for name in &args.packages {
+            if !pkgs.iter().any(|p| &p.name == name) {
+                return Err(anyhow!("{name} is not found in mops.lock"));
+            }
+        }
+        pkgs.retain(|p| args.packages.contains(&p.name));
+    }
+    check_online("check for updates")?;
+    let service = get_registry(env, agent);
+    let (registry, others): (Vec<_>, Vec<_>) = pkgs
+        .into_iter()
+        .partition(|p| matches!(p.get_type(), PackageType::Mops { .. }));
+    let mut versions = Vec::new();
+    if args.compatible {
+        // Ask mops for the highest version with the same major version in a single call
+        let batch: Vec<_> = registry
+            .iter()
+            .map(|p| (p.name.clone(), p.version.clone().unwrap()))
+            .collect();
+        if !batch.is_empty() {
+            let latest = service
+                .get_highest_compatible_versions(batch.clone())
+                .await?;
+            for (name, ver) in batch {
+                if let Some(latest) = latest.get(&name) {
+                    if *latest != ver {
+                        versions.push((name, ver, latest.clone()));
+                    }
+                }
+            }
+        }
+    } else {
+        let mut futures = Vec::new();
+        for pkg in registry {
+            futures.push(get_latest_package_version(service.clone(), pkg, false));
+        }
+        versions.extend(try_join_all(futures).await?.into_iter().flatten());
+    }
     let mut futures = Vec::new();
-    for pkg in pkgs {
-        futures.push(get_latest_package_version(service.clone(), pkg));
+    for pkg in others {
+        futures.push(get_latest_package_version(
+            service.clone(),
+            pkg,
+            args.compatible,
+        ));
+    }
+    versions.extend(try_join_all(futures).await?.into_iter().flatten());
+    for (name, current, latest) in &versions {
+        emit(Event::UpdateAvailable {
+            name,
+            current,
+            latest,
+        });
+    }
+    if args.dry_run {
+        for ver in versions {
+            println(None, "stdout", &format!("{}@{} -> {}", ver.0, ver.1, ver.2));
+        }
+        return Ok(());
+    }
+    if versions.is_empty() {
+        println(None, "stdout", "All dependencies are up-to-date");
+        return Ok(());
     }
-    let versions = try_join_all(futures).await?.into_iter().flatten();
-    for ver in versions {
-        println!("{}@{} -> {}", ver.0, ver.1, ver.2);
+    let mut doc = read_mops_toml(env)?;
+    for (name, cur, latest) in &versions {
+        let spec = doc["dependencies"]
+            .get(name)
+            .and_then(|v| v.as_str())
+            .map(|s| s.to_string());
+        match spec {
+            Some(spec) => {
+                match upgrade_spec(&spec, latest) {
+                    SpecUpgrade::Skip => {
+                        println(
+                            None,
+                            "stdout",
+                            &format!(
+                                "{:>12} {name}@{cur} -> {latest} is available, but {name} = \"{spec}\" is a range in mops.toml. Update it manually.",
+                                style("Skipped").yellow().bold()
+                            ),
+                        );
+                        continue;
+                    }
+                    SpecUpgrade::Rewrite(new_spec) => doc["dependencies"][name] = value(new_spec),
+                    SpecUpgrade::Unchanged => (),
+                }
+                println(
+                    None,
+                    "stdout",
+                    &format!(
+                        "{:>12} {name}@{cur} -> {latest}",
+                        style("Updating").green().bold()
+                    ),
+                );
+            }
+            None => println(
+                None,
+                "stdout",
+                &format!(
+                    "{:>12} {name}@{cur} -> {latest} is available, but {name} is not a direct dependency in mops.toml",
+                    style("Skipped").yellow().bold()
+                ),
+            ),
+        }
     }
+    fs::write(env.get_mops_toml_path(), doc.to_string())?;
+    update_mops_lock(agent, env).await?;
+    download_packages_from_lock(agent, env).await?;
     Ok(())
 }
+enum SpecUpgrade {
+    /// Write the new spec to mops.toml
+    Rewrite(String),
+    /// The spec already resolves to the latest version when updating mops.lock, e.g., `*` or a git branch
+    Unchanged,
+    /// A range declared by the user, which is not overwritten
+    Skip,
+}
+/// Rewrite the dependency spec in mops.toml to the new version, tag or commit
+fn upgrade_spec(spec: &str, latest: &str) -> SpecUpgrade {
+    use semver::Op;
+    if spec.starts_with("https://github.com") {
+        let is_commit = latest.len() == 40 && latest.chars().all(|c| c.is_ascii_hexdigit());
+        let (url, fragment) = match spec.split_once('#') {
+            Some((url, fragment)) => (url, Some(fragment)),
+            None => (spec, None),
+        };
+        match (fragment, is_commit) {
+            // Follows the default branch. The latest commit is resolved when updating mops.lock.
+            (None, _) => SpecUpgrade::Unchanged,
+            (Some(fragment), true) => match fragment.split_once('@') {
+                Some((tag, _)) => SpecUpgrade::Rewrite(format!("{url}#{tag}@{latest}")),
+                None => SpecUpgrade::Unchanged,
+            },
+            (Some(_), false) => SpecUpgrade::Rewrite(format!("{url}#{latest}")),
+        }
+    } else if Path::new(spec).exists() {
+        SpecUpgrade::Unchanged
+    } else if parse_version(spec).is_some() {
+        SpecUpgrade::Rewrite(latest.to_string())
+    } else {
+        match VersionReq::parse(spec) {
+            Ok(req) if req == VersionReq::STAR => SpecUpgrade::Unchanged,
+            // Keep the operator of a single ^, ~ or = requirement
+            Ok(req)
+                if req.comparators.len() == 1
+                    && matches!(req.comparators[0].op, Op::Caret | Op::Tilde | Op::Exact) =>
+            {
+                let op: String = spec.chars().take_while(|c| "^~=".contains(*c)).collect();
+                SpecUpgrade::Rewrite(format!("{op}{latest}"))
+            }
+            _ => SpecUpgrade::Skip,
+        }
+    }
+}
 async fn get_latest_package_version(
-    service: Rc<mops::Service<'_>>,
+    service: Rc<dyn Registry + '_>,
     pkg: Package,
+    compatible: bool,
 ) -> Result<Option<(String, String, String)>> {
     match pkg.get_type() {
         PackageType::Mops { .. } => {
             let latest = service
                 .get_highest_version(&pkg.name)
                 .await?
-                .into_result()
-                .map_err(Error::msg)?;
+                .ok_or_else(|| anyhow!("Cannot find {} on mops", pkg.name))?;
             let ver = pkg.version.unwrap();
             Ok(if latest == ver {
                 None
@@ -461,7 +966,14 @@ async fn get_latest_package_version(
         PackageType::Repo(info) => {
             let latest = get_latest_tag(&info.repo).await?;
             let tag = info.tag.clone();
-            Ok(if latest == tag {
+            let is_compatible = || match (
+                guess_version_from_tag(&tag),
+                guess_version_from_tag(&latest),
+            ) {
+                (Some(cur), Some(new)) => cur.major == new.major,
+                _ => false,
+            };
+            Ok(if latest == tag || (compatible && !is_compatible()) {
                 let cur_commit = info.commit.clone();
                 let latest = get_latest_commit(&info.repo, &info.tag).await?;
                 if cur_commit == latest {
@@ -478,13 +990,24 @@ async fn get_latest_package_version(
 }
 pub async fn download_packages_from_lock(agent: &Agent, env: &Env) -> Result<()> {
     let lock = env.get_mops_lock_path();
-    let pkgs = parse_mops_lock(&lock)?.package;
-    let service = Rc::new(mops::Service(mops::CANISTER_ID, agent));
-    let bar = Rc::new(create_bar(pkgs.len()));
+    let mut lock_file = read_mops_lock(&lock)?;
+    let registry = get_registry(env, agent);
+    let bar = Rc::new(create_bar(lock_file.package.len()));
     bar.set_prefix("Downloading packages");
     let mut mop_futures = Vec::new();
     let mut git_futures = Vec::new();
-    for pkg in pkgs {
+    for pkg in &lock_file.package {
+        if let PackageType::Mops { id, .. } = pkg.get_type() {
+            // Packages of another registry cannot be downloaded, and may differ from the ones in the cache
+            if !registry.has_source(id) {
+                bar.finish_and_clear();
+                return Err(anyhow!(
+                    "{} in mops.lock is from {id}, but the current registry is {}. Delete mops.lock to resolve the packages from the current registry.",
+                    pkg.get_label(),
+                    registry.describe()
+                ));
+            }
+        }
         bar.set_message(pkg.name.clone());
         let subpath = pkg.get_path();
         let path = env.cache_dir.join(subpath);
@@ -493,21 +1016,21 @@ pub async fn download_packages_from_lock(agent: &Agent, env: &Env) -> Result<()>
             continue;
         }
         match pkg.get_type() {
-            PackageType::Mops { id, .. } => {
-                let id = Principal::from_text(id)?;
+            PackageType::Mops { id, ver } => {
                 mop_futures.push(download_mops_package(
                     path,
-                    pkg.name,
-                    pkg.version.unwrap(),
-                    service.clone(),
-                    id,
+                    pkg.name.clone(),
+                    ver.to_string(),
+                    registry.clone(),
+                    id.to_string(),
                     bar.clone(),
                 ));
             }
-            PackageType::Repo(_) => {
+            PackageType::Repo(repo) => {
                 git_futures.push(download_github_package(
                     path,
-                    pkg.repo.unwrap(),
+                    pkg.name.clone(),
+                    repo.clone(),
                     bar.clone(),
                 ));
             }
@@ -519,25 +1042,57 @@ pub async fn download_packages_from_lock(agent: &Agent, env: &Env) -> Result<()>
     try_join_all(mop_futures).await?;
     try_join_all(git_futures).await?;
     bar.finish_and_clear();
+    // Verify the content of both cached and newly downloaded packages
+    let mut updated = false;
+    for pkg in lock_file.package.iter_mut() {
+        if let PackageType::Local(_) = pkg.get_type() {
+            continue;
+        }
+        let path = env.cache_dir.join(pkg.get_path());
+        // The DONE markers are written to the package root, which is hashed when base_dir is empty
+        let at_root = Path::new(&pkg.base_dir)
+            .components()
+            .all(|c| c == std::path::Component::CurDir);
+        let checksum = hash_dir(&path.join(&pkg.base_dir), at_root)?;
+        match &pkg.checksum {
+            Some(expected) if *expected != checksum => {
+                // Make sure the package is downloaded again next time
+                let _ = fs::remove_file(path.join(pkg.get_done_file()));
+                return Err(anyhow!(
+                    "Checksum mismatch for {}: mops.lock has {expected}, but the downloaded content has {checksum}. The package in {} may be corrupted or tampered with.",
+                    pkg.get_label(),
+                    path.display()
+                ));
+            }
+            Some(_) => (),
+            None => {
+                pkg.checksum = Some(checksum);
+                updated = true;
+            }
+        }
+    }
+    if updated {
+        write_mops_lock(&lock, &lock_file)?;
+    }
     Ok(())
 }
 async fn download_mops_package(
     base_path: PathBuf,
     lib: String,
     version: String,
-    service: Rc<mops::Service<'_>>,
-    storage_id: Principal,
+    registry: Rc<dyn Registry + '_>,
+    source: String,
     bar: Rc<ProgressBar>,
 ) -> Result<()> {
-    let ids = service
-        .get_file_ids(&lib, &version)
-        .await?
-        .into_result()
-        .map_err(Error::msg)?;
+    let ids = registry.get_file_ids(&lib, &version).await?;
     let mut futures = Vec::new();
-    let storage = Rc::new(storage::Service(storage_id, service.1));
     for id in ids {
-        futures.push(download_file(base_path.clone(), id, storage.clone()));
+        futures.push(download_file(
+            base_path.clone(),
+            id,
+            &source,
+            registry.clone(),
+        ));
     }
     try_join_all(futures).await?;
     fs::write(base_path.join("DONE"), "")?;
@@ -546,35 +1101,27 @@ async fn download_mops_package(
         "stdout",
         &format!("{:>12} {lib}@{version}", style("Downloaded").green().bold()),
     );
+    emit(Event::PackageDownloaded {
+        name: &lib,
+        version: &version,
+    });
     bar.inc(1);
     Ok(())
 }
 async fn download_file(
     base_path: PathBuf,
     id: String,
-    storage: Rc<storage::Service<'_>>,
+    source: &str,
+    registry: Rc<dyn Registry + '_>,
 ) -> Result<()> {
-    let meta = storage
-        .get_file_meta(&id)
-        .await?
-        .into_result()
-        .map_err(Error::msg)?;
-    let mut blob = Vec::new();
-    for i in 0..meta.chunk_count {
-        let chunk = storage
-            .download_chunk(&id, &i.into())
-            .await?
-            .into_result()
-            .map_err(Error::msg)?;
-        blob.extend(chunk);
-    }
-    let path = base_path.join(meta.path);
+    let (path, blob) = registry.download_file(source, &id).await?;
+    let path = base_path.join(path);
     fs::create_dir_all(path.parent().unwrap())?;
     fs::write(path, blob)?;
     Ok(())
 }
 #[allow(clippy::enum_variant_names)]
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 enum Mops {
     Mops { name: String, version: String },
     Repo { name: String, repo: String },
@@ -582,8 +1129,10 @@ enum Mops {
 }
 #[derive(Debug, Serialize, Deserialize)]
 struct CanisterInfo {
+    /// The canister_id of the selected network
     canister_id: Option<String>,
     name: Option<String>,
+    network: Option<String>,
     candid: Option<String>,
     output: Option<String>,
 }
@@ -593,7 +1142,8 @@ struct MopsConfig {
     dependencies: Vec<Mops>,
     canisters: Vec<CanisterInfo>,
 }
-fn parse_mops_toml(base_path: &Path, str: &str) -> Result<MopsConfig> {
+/// `network` selects the canister_id when a canister has an id for each network
+fn parse_mops_toml(base_path: &Path, str: &str, network: &str) -> Result<MopsConfig> {
     let doc = str.parse::<ImDocument<_>>()?;
     let mut mops = Vec::new();
     let mut version = None;
@@ -612,22 +1162,7 @@ fn parse_mops_toml(base_path: &Path, str: &str) -> Result<MopsConfig> {
                 .ok_or_else(|| anyhow!("invalid version"))?
                 .as_str()
                 .ok_or_else(|| anyhow!("invalid version"))?;
-            if version.starts_with("https://github.com") {
-                mops.push(Mops::Repo {
-                    name: lib.to_string(),
-                    repo: version.to_string(),
-                });
-            } else if Path::new(version).exists() {
-                mops.push(Mops::Local {
-                    name: lib.to_string(),
-                    path: version.to_string(),
-                });
-            } else {
-                mops.push(Mops::Mops {
-                    name: lib.to_string(),
-                    version: version.to_string(),
-                });
-            }
+            mops.push(parse_dependency(lib, version));
         }
     }
     let mut canisters = Vec::new();
@@ -648,10 +1183,31 @@ fn parse_mops_toml(base_path: &Path, str: &str) -> Result<MopsConfig> {
     }
     if let Some(item) = doc.get("canister") {
         for canister in item.as_array_of_tables().unwrap().iter() {
-            let canister_id = get_field(canister, "canister_id");
             let name = get_field(canister, "name");
+            let (canister_id, selected) = match canister.get("canister_id") {
+                None => (None, None),
+                Some(item) => match item.as_str() {
+                    Some(id) => (Some(id.to_string()), None),
+                    None => {
+                        let ids = item
+                            .as_table_like()
+                            .ok_or_else(|| anyhow!("invalid canister_id in \"{canister}\""))?;
+                        let name = name.as_ref().ok_or_else(|| {
+                            anyhow!(
+                                "name is required when canister_id is a table in \"{canister}\""
+                            )
+                        })?;
+                        let id = ids.get(network).and_then(|id| id.as_str()).ok_or_else(|| {
+                            anyhow!("canister {name} has no canister_id for network {network}")
+                        })?;
+                        (Some(id.to_string()), Some(network.to_string()))
+                    }
+                },
+            };
             let candid = get_field(canister, "candid").map(|p| resolve_path(base_path, &p));
-            let output = get_field(canister, "output").map(|p| resolve_path(base_path, &p));
+            // The output file is generated, so it may not exist yet
+            let output = get_field(canister, "output")
+                .map(|p| base_path.join(p).to_string_lossy().to_string());
             if canister_id.is_none() {
                 if candid.is_none() {
                     return Err(anyhow!(
@@ -660,12 +1216,11 @@ fn parse_mops_toml(base_path: &Path, str: &str) -> Result<MopsConfig> {
                 } else if output.is_none() {
                     return Err(anyhow!("output is required in \"{canister}\""));
                 }
-            } else if output.is_some() {
-                return Err(anyhow!("output is not allowed in \"{canister}\""));
             }
             canisters.push(CanisterInfo {
                 canister_id,
                 name,
+                network: selected,
                 candid,
                 output,
             });
@@ -677,11 +1232,134 @@ fn parse_mops_toml(base_path: &Path, str: &str) -> Result<MopsConfig> {
         canisters,
     })
 }
+fn parse_dependency(name: &str, spec: &str) -> Mops {
+    if spec.starts_with("https://github.com") {
+        Mops::Repo {
+            name: name.to_string(),
+            repo: spec.to_string(),
+        }
+    } else if Path::new(spec).exists() {
+        Mops::Local {
+            name: name.to_string(),
+            path: spec.to_string(),
+        }
+    } else {
+        Mops::Mops {
+            name: name.to_string(),
+            version: spec.to_string(),
+        }
+    }
+}
+/// The dependency graph recorded in mops.lock, keyed by package name
+pub struct LockGraph {
+    pub roots: Vec<Dependency>,
+    pub packages: BTreeMap<String, LockNode>,
+}
+pub struct LockNode {
+    pub label: String,
+    pub dependencies: Vec<Dependency>,
+}
+/// A dependency entry and how the version selected in mops.lock relates to it
+pub struct Dependency {
+    pub name: String,
+    pub spec: String,
+    pub status: DependencyStatus,
+}
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DependencyStatus {
+    /// The selected package is the one fetched for this entry
+    Exact,
+    /// The entry is satisfied by a newer version requested by another package
+    Deduplicated,
+    /// The entry is not satisfied, because mops.toml pins another version
+    Overridden,
+    /// The package is not in mops.lock
+    Missing,
+}
+pub fn get_lock_graph(env: &Env) -> Result<LockGraph> {
+    let lock = env.get_mops_lock_path();
+    if !lock.exists() {
+        return Err(anyhow!(
+            "mops.lock not found. Run mops-cli build or mops-cli update to generate it."
+        ));
+    }
+    let pkgs = parse_mops_lock(&lock)?;
+    let str = fs::read_to_string(env.get_mops_toml_path())?;
+    let toml = parse_mops_toml(&env.project_root, &str, &env.network.name)?;
+    let by_name: BTreeMap<_, _> = pkgs.package.iter().map(|p| (p.name.as_str(), p)).collect();
+    let to_dependency = |name: &str, spec: &str| {
+        let status = match by_name.get(name) {
+            None => DependencyStatus::Missing,
+            Some(pkg) => pkg.get_status(spec),
+        };
+        Dependency {
+            name: name.to_string(),
+            spec: spec.to_string(),
+            status,
+        }
+    };
+    let roots = toml
+        .dependencies
+        .iter()
+        .map(|m| {
+            let (name, spec) = m.get_entry();
+            to_dependency(&name, &spec)
+        })
+        .collect();
+    let packages = pkgs
+        .package
+        .iter()
+        .map(|pkg| {
+            let dependencies = pkg
+                .dependencies
+                .iter()
+                .map(|(name, spec)| to_dependency(name, spec))
+                .collect();
+            let node = LockNode {
+                label: pkg.get_label(),
+                dependencies,
+            };
+            (pkg.name.clone(), node)
+        })
+        .collect();
+    Ok(LockGraph { roots, packages })
+}
+/// Hash of mops.lock and the local packages, which are not recorded in mops.lock
+pub fn get_lock_hash(env: &Env) -> Result<String> {
+    let lock = env.get_mops_lock_path();
+    let mut res = fs::read_to_string(&lock).unwrap_or_default();
+    let pkgs = read_mops_lock(&lock)?;
+    for pkg in pkgs.package {
+        if let PackageType::Local(path) = pkg.get_type() {
+            res.push_str(&hash_dir(&Path::new(path).join(&pkg.base_dir), false)?);
+        }
+    }
+    // mops.lock only records the path of local candid files, which are copied to target/idl on every build
+    for c in pkgs.canister.into_iter().flatten() {
+        if c.timestamp.is_none() {
+            let mut hasher = Sha256::new();
+            hasher.update(fs::read(&c.candid)?);
+            res.push_str(&to_hex(hasher));
+        }
+    }
+    Ok(res)
+}
 fn parse_mops_lock(lock: &Path) -> Result<Packages> {
     let str = fs::read_to_string(lock)?;
     let doc = str.parse::<ImDocument<_>>()?;
-    let lock = toml_edit::de::from_document::<Packages>(doc)?;
-    Ok(lock)
+    toml_edit::de::from_document::<Packages>(doc).map_err(|e| {
+        anyhow!(
+            "Failed to parse {}: {e}\nIt may be generated by an older version of mops-cli. Build without --lock or --offline to regenerate it.",
+            lock.display()
+        )
+    })
+}
+/// Same as parse_mops_lock, but returns an empty lock when mops.lock does not exist yet
+fn read_mops_lock(lock: &Path) -> Result<Packages> {
+    if !lock.exists() {
+        return Ok(Packages::default());
+    }
+    parse_mops_lock(lock)
 }
 enum PackageType<'a> {
     Mops { ver: &'a str, id: &'a str },
@@ -689,7 +1367,7 @@ enum PackageType<'a> {
     Repo(&'a RepoInfo),
 }
 impl Package {
-    fn get_type(&self) -> PackageType {
+    fn get_type(&self) -> PackageType<'_> {
         if self.source.starts_with("file://") {
             let local = self.source.strip_prefix("file://").unwrap();
             PackageType::Local(local)
@@ -710,8 +1388,19 @@ impl Package {
             PackageType::Local(local) => format!("{}-{}", self.name, local),
         }
     }
+    fn get_label(&self) -> String {
+        match self.get_type() {
+            PackageType::Mops { ver, .. } => format!("{}@{}", self.name, ver),
+            PackageType::Repo(repo) => format!("{}@{}#{}", self.name, repo.repo, repo.tag),
+            PackageType::Local(local) => format!("{}@{}", self.name, local),
+        }
+    }
     fn get_path(&self) -> String {
         match self.get_type() {
+            // Packages from a directory registry are not shared with the mops canister
+            PackageType::Mops { ver, id } if id.starts_with("dir://") => {
+                format!("dir/{}-{}", self.name, ver)
+            }
             PackageType::Mops { ver, .. } => format!("mops/{}-{}", self.name, ver),
             PackageType::Repo(repo) => {
                 let repo_name = repo.repo.replace('/', "-");
@@ -720,6 +1409,41 @@ impl Package {
             PackageType::Local(local) => local.to_string(),
         }
     }
+    /// Compare the package with a dependency entry of the same name
+    fn get_status(&self, spec: &str) -> DependencyStatus {
+        let satisfied = match (parse_dependency(&self.name, spec), self.get_type()) {
+            (Mops::Mops { version, .. }, PackageType::Mops { ver, .. }) => {
+                let fetch = get_fetch_version(&version);
+                if fetch.as_deref() == Some(ver) {
+                    return DependencyStatus::Exact;
+                }
+                match (VersionReq::parse(&version), parse_version(ver)) {
+                    // Without a lower bound, e.g., `*`, the highest version in the range is fetched, so any match is exact
+                    (Ok(req), Some(ver)) if req.matches(&ver) && fetch.is_none() => {
+                        return DependencyStatus::Exact
+                    }
+                    (Ok(req), Some(ver)) => req.matches(&ver),
+                    _ => false,
+                }
+            }
+            (Mops::Repo { repo, .. }, PackageType::Repo(info)) => {
+                let url = repo.strip_prefix("https://github.com/").unwrap_or(&repo);
+                let (url, tag) = url.split_once('#').unwrap_or((url, ""));
+                let tag = tag.split('@').next().unwrap_or_default();
+                url.trim_end_matches(".git").starts_with(&info.repo)
+                    && (tag.is_empty() || tag == info.tag)
+            }
+            (Mops::Local { path, .. }, PackageType::Local(local)) => {
+                fs::canonicalize(&path).is_ok_and(|p| p == Path::new(local))
+            }
+            _ => false,
+        };
+        match (satisfied, self.get_type()) {
+            (true, PackageType::Mops { .. }) => DependencyStatus::Deduplicated,
+            (true, _) => DependencyStatus::Exact,
+            (false, _) => DependencyStatus::Overridden,
+        }
+    }
     fn get_done_file(&self) -> String {
         // Make sure this returns the same name as each download function
         match self.get_type() {
@@ -732,17 +1456,18 @@ impl Package {
 impl Canister {
     fn get_key(&self) -> String {
         // technically it's self.name.unwrap_or(canister_id). Need to think about the logic for dedup
-        self.name
-            .as_ref()
-            .unwrap_or_else(|| {
-                self.canister_id
-                    .as_ref()
-                    .unwrap_or_else(|| self.output.as_ref().unwrap())
-            })
-            .clone()
+        let key = self.name.as_ref().unwrap_or_else(|| {
+            self.canister_id
+                .as_ref()
+                .unwrap_or_else(|| self.output.as_ref().unwrap())
+        });
+        match &self.network {
+            Some(network) => format!("{key}@{network}"),
+            None => key.clone(),
+        }
     }
     fn no_need_to_update(&self, new: &CanisterInfo) -> bool {
-        if self.canister_id != new.canister_id {
+        if self.canister_id != new.canister_id || self.output != new.output {
             return false;
         }
         if self.timestamp.is_some() {
@@ -754,17 +1479,31 @@ impl Canister {
 }
 impl CanisterInfo {
     fn get_key(&self) -> String {
-        self.name
-            .as_ref()
-            .unwrap_or_else(|| {
-                self.canister_id
-                    .as_ref()
-                    .unwrap_or_else(|| self.output.as_ref().unwrap())
-            })
-            .clone()
+        let key = self.name.as_ref().unwrap_or_else(|| {
+            self.canister_id
+                .as_ref()
+                .unwrap_or_else(|| self.output.as_ref().unwrap())
+        });
+        match &self.network {
+            Some(network) => format!("{key}@{network}"),
+            None => key.clone(),
+        }
     }
 }
 impl Mops {
+    fn get_name(&self) -> &str {
+        match self {
+            Mops::Mops { name, .. } | Mops::Repo { name, .. } | Mops::Local { name, .. } => name,
+        }
+    }
+    /// The (name, spec) entry as written in the dependencies table of mops.toml
+    fn get_entry(&self) -> (String, String) {
+        match self {
+            Mops::Mops { name, version } => (name.clone(), version.clone()),
+            Mops::Repo { name, repo } => (name.clone(), repo.clone()),
+            Mops::Local { name, path } => (name.clone(), path.clone()),
+        }
+    }
     fn get_display_key(&self) -> String {
         // only for displaying in dependencies, not used for dedup
         match self {
@@ -774,3 +1513,70 @@ impl Mops {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{get_fetch_version, resolve_fetch_version, DependencyStatus, Package};
+    use crate::registry::DirRegistry;
+
+    #[test]
+    fn fetch_version() {
+        let fetch = |spec| get_fetch_version(spec);
+        assert_eq!(fetch("0.11.3").as_deref(), Some("0.11.3"));
+        assert_eq!(fetch("^0.11.3").as_deref(), Some("0.11.3"));
+        assert_eq!(fetch("~1.2.1").as_deref(), Some("1.2.1"));
+        assert_eq!(fetch("=1.0.0").as_deref(), Some("1.0.0"));
+        assert_eq!(fetch(">=0.5.0, <0.6.0").as_deref(), Some("0.5.0"));
+        assert_eq!(fetch("<=0.6.0, >=0.5.0").as_deref(), Some("0.5.0"));
+        assert_eq!(fetch("1.0.0-beta.1").as_deref(), Some("1.0.0-beta.1"));
+        // No lower bound, use the highest version on mops that satisfies the spec
+        assert_eq!(fetch("*"), None);
+        assert_eq!(fetch("<0.6.0"), None);
+        assert_eq!(fetch(">0.5.0"), None);
+        // The lower bound may not be published
+        assert_eq!(fetch("^0.11"), None);
+        assert_eq!(fetch("~1.2"), None);
+        // Not a semver range
+        assert_eq!(fetch("latest").as_deref(), Some("latest"));
+    }
+
+    #[tokio::test]
+    async fn fetch_version_from_registry() {
+        let dir = std::env::temp_dir().join(format!("mops-cli-registry-{}", std::process::id()));
+        for ver in ["0.5.3", "0.6.0", "0.11.2"] {
+            std::fs::create_dir_all(dir.join("base").join(ver)).unwrap();
+        }
+        let registry = DirRegistry(dir.clone());
+        let fetch = |spec| resolve_fetch_version(&registry, "base", spec);
+        assert_eq!(fetch("<0.6.0").await.unwrap(), "0.5.3");
+        assert_eq!(fetch("<=0.6.0, >=0.5").await.unwrap(), "0.6.0");
+        assert_eq!(fetch("^0.11").await.unwrap(), "0.11.2");
+        assert_eq!(fetch("*").await.unwrap(), "0.11.2");
+        assert_eq!(fetch("0.5.0").await.unwrap(), "0.5.0");
+        assert_eq!(
+            fetch("^0.12").await.unwrap_err().to_string(),
+            "Cannot find a version of base that satisfies ^0.12 on mops"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn dependency_status() {
+        let pkg = Package {
+            name: "base".to_string(),
+            version: Some("0.11.3".to_string()),
+            source: "dir:///registry".to_string(),
+            base_dir: "src".to_string(),
+            repo: None,
+            checksum: None,
+            dependencies: Default::default(),
+        };
+        assert_eq!(pkg.get_status("0.11.3"), DependencyStatus::Exact);
+        assert_eq!(pkg.get_status("^0.11.3"), DependencyStatus::Exact);
+        // The highest version is fetched when there is no lower bound
+        assert_eq!(pkg.get_status("*"), DependencyStatus::Exact);
+        assert_eq!(pkg.get_status("<0.12.0"), DependencyStatus::Exact);
+        assert_eq!(pkg.get_status("^0.11.1"), DependencyStatus::Deduplicated);
+        assert_eq!(pkg.get_status("0.10.0"), DependencyStatus::Overridden);
+    }
+}
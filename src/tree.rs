@@ -0,0 +1,258 @@
+use crate::env::Env;
+use crate::toml::{get_lock_graph, Dependency, DependencyStatus, LockGraph};
+use crate::utils::println;
+use anyhow::{anyhow, Result};
+use console::style;
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq, Eq)]
+pub enum TreeFormat {
+    /// Indented tree
+    Text,
+    /// Graphviz dot format
+    Dot,
+}
+/// The node of mops.toml in the dot graph
+const ROOT: &str = "mops.toml";
+
+pub fn tree(env: &Env, args: crate::TreeArg) -> Result<()> {
+    let graph = get_lock_graph(env)?;
+    let project = env
+        .project_root
+        .file_name()
+        .map_or(ROOT.to_string(), |n| n.to_string_lossy().to_string());
+    if let Some(name) = &args.invert {
+        if !graph.packages.contains_key(name) {
+            return Err(anyhow!("Package {name} not found in mops.lock"));
+        }
+    }
+    let res = match args.format {
+        TreeFormat::Text => {
+            let mut lines = Vec::new();
+            let mut visited = BTreeSet::new();
+            match &args.invert {
+                None => {
+                    lines.push(project);
+                    print_tree(&graph, &graph.roots, "", &mut visited, &mut lines);
+                }
+                Some(name) => {
+                    let reverse = reverse_edges(&graph);
+                    lines.push(graph.packages[name].label.clone());
+                    visited.insert(name.clone());
+                    print_inverted(
+                        &graph,
+                        &reverse,
+                        name,
+                        &project,
+                        "",
+                        &mut visited,
+                        &mut lines,
+                    );
+                }
+            }
+            lines.join("\n")
+        }
+        TreeFormat::Dot => print_dot(&graph, &project, args.invert.as_deref()),
+    };
+    println(None, "stdout", &res);
+    Ok(())
+}
+pub fn why(env: &Env, args: crate::WhyArg) -> Result<()> {
+    let graph = get_lock_graph(env)?;
+    let name = &args.package;
+    let Some(node) = graph.packages.get(name) else {
+        return Err(anyhow!("Package {name} not found in mops.lock"));
+    };
+    let project = env
+        .project_root
+        .file_name()
+        .map_or(ROOT.to_string(), |n| n.to_string_lossy().to_string());
+    let mut paths = Vec::new();
+    find_paths(&graph, &graph.roots, name, &mut vec![project], &mut paths);
+    let mut lines = vec![format!(
+        "{:>12} {}",
+        style("Selected").green().bold(),
+        node.label
+    )];
+    for (path, dep) in paths {
+        lines.push(format!(
+            "{:>12} {} -> {name} = \"{}\"{}",
+            "",
+            path.join(" -> "),
+            dep.spec,
+            match dep.status {
+                DependencyStatus::Exact => String::new(),
+                DependencyStatus::Deduplicated => format!(" {}", style("(deduplicated)").dim()),
+                DependencyStatus::Overridden => format!(" {}", style("(overridden)").yellow()),
+                DependencyStatus::Missing => format!(" {}", style("(missing)").red()),
+            }
+        ));
+    }
+    println(None, "stdout", &lines.join("\n"));
+    Ok(())
+}
+/// All paths from mops.toml to the package, with the dependency entry at the end of each path
+fn find_paths<'a>(
+    graph: &'a LockGraph,
+    deps: &'a [Dependency],
+    target: &str,
+    path: &mut Vec<String>,
+    paths: &mut Vec<(Vec<String>, &'a Dependency)>,
+) {
+    for dep in deps {
+        if dep.name == target {
+            paths.push((path.clone(), dep));
+            continue;
+        }
+        let Some(node) = graph.packages.get(&dep.name) else {
+            continue;
+        };
+        // Avoid cycles
+        if path.contains(&node.label) {
+            continue;
+        }
+        path.push(node.label.clone());
+        find_paths(graph, &node.dependencies, target, path, paths);
+        path.pop();
+    }
+}
+fn print_tree(
+    graph: &LockGraph,
+    deps: &[Dependency],
+    prefix: &str,
+    visited: &mut BTreeSet<String>,
+    lines: &mut Vec<String>,
+) {
+    for (i, dep) in deps.iter().enumerate() {
+        let last = i == deps.len() - 1;
+        let branch = if last { "└── " } else { "├── " };
+        let node = graph.packages.get(&dep.name);
+        let label = node.map_or(dep.name.as_str(), |n| n.label.as_str());
+        let mut line = format!("{prefix}{branch}{label}{}", annotate(dep));
+        let Some(node) = node else {
+            lines.push(line);
+            continue;
+        };
+        if !visited.insert(dep.name.clone()) {
+            if !node.dependencies.is_empty() {
+                line.push_str(" (*)");
+            }
+            lines.push(line);
+            continue;
+        }
+        lines.push(line);
+        let prefix = format!("{prefix}{}", if last { "    " } else { "│   " });
+        print_tree(graph, &node.dependencies, &prefix, visited, lines);
+    }
+}
+/// Package name to the packages that depend on it. None is mops.toml.
+fn reverse_edges(graph: &LockGraph) -> BTreeMap<&str, Vec<(Option<&str>, &Dependency)>> {
+    let mut res: BTreeMap<&str, Vec<_>> = BTreeMap::new();
+    for dep in &graph.roots {
+        res.entry(dep.name.as_str()).or_default().push((None, dep));
+    }
+    for (name, node) in &graph.packages {
+        for dep in &node.dependencies {
+            res.entry(dep.name.as_str())
+                .or_default()
+                .push((Some(name.as_str()), dep));
+        }
+    }
+    res
+}
+fn print_inverted(
+    graph: &LockGraph,
+    reverse: &BTreeMap<&str, Vec<(Option<&str>, &Dependency)>>,
+    name: &str,
+    project: &str,
+    prefix: &str,
+    visited: &mut BTreeSet<String>,
+    lines: &mut Vec<String>,
+) {
+    let parents = reverse.get(name).map_or(&[][..], |v| v.as_slice());
+    for (i, (parent, dep)) in parents.iter().enumerate() {
+        let last = i == parents.len() - 1;
+        let branch = if last { "└── " } else { "├── " };
+        let Some(parent) = parent else {
+            lines.push(format!("{prefix}{branch}{project}{}", annotate(dep)));
+            continue;
+        };
+        let label = &graph.packages[*parent].label;
+        let mut line = format!("{prefix}{branch}{label}{}", annotate(dep));
+        if !visited.insert(parent.to_string()) {
+            line.push_str(" (*)");
+            lines.push(line);
+            continue;
+        }
+        lines.push(line);
+        let prefix = format!("{prefix}{}", if last { "    " } else { "│   " });
+        print_inverted(graph, reverse, parent, project, &prefix, visited, lines);
+    }
+}
+fn annotate(dep: &Dependency) -> String {
+    match dep.status {
+        DependencyStatus::Exact => String::new(),
+        DependencyStatus::Deduplicated => format!(
+            " {}",
+            style(format!("(deduplicated from {})", dep.spec)).dim()
+        ),
+        DependencyStatus::Overridden => format!(
+            " {}",
+            style(format!("(overridden, requires {})", dep.spec)).yellow()
+        ),
+        DependencyStatus::Missing => format!(" {}", style("(missing from mops.lock)").red()),
+    }
+}
+fn print_dot(graph: &LockGraph, project: &str, invert: Option<&str>) -> String {
+    // With --invert, only keep the packages that depend on the given package
+    let keep: Option<BTreeSet<&str>> = invert.map(|name| {
+        let reverse = reverse_edges(graph);
+        let mut keep = BTreeSet::new();
+        let mut stack = vec![name];
+        while let Some(name) = stack.pop() {
+            if !keep.insert(name) {
+                continue;
+            }
+            for (parent, _) in reverse.get(name).into_iter().flatten() {
+                stack.push(parent.unwrap_or(ROOT));
+            }
+        }
+        keep
+    });
+    let included = |name: &str| keep.as_ref().is_none_or(|k| k.contains(name));
+    let mut res = vec!["digraph {".to_string()];
+    if included(ROOT) {
+        res.push(format!("    \"{ROOT}\" [label=\"{project}\", shape=box];"));
+    }
+    for (name, node) in &graph.packages {
+        if included(name) {
+            res.push(format!("    \"{name}\" [label=\"{}\"];", node.label));
+        }
+    }
+    let edges = graph
+        .roots
+        .iter()
+        .map(|dep| (ROOT, dep))
+        .chain(graph.packages.iter().flat_map(|(name, node)| {
+            node.dependencies
+                .iter()
+                .map(move |dep| (name.as_str(), dep))
+        }));
+    for (from, dep) in edges {
+        if !included(from) || !included(&dep.name) {
+            continue;
+        }
+        let attr = match dep.status {
+            DependencyStatus::Exact => String::new(),
+            DependencyStatus::Deduplicated => ", style=dashed".to_string(),
+            DependencyStatus::Overridden | DependencyStatus::Missing => ", color=red".to_string(),
+        };
+        res.push(format!(
+            "    \"{from}\" -> \"{}\" [label=\"{}\"{attr}];",
+            dep.name,
+            dep.spec.replace('"', "\\\"")
+        ));
+    }
+    res.push("}".to_string());
+    res.join("\n")
+}
@@ -0,0 +1,161 @@
+use crate::env::{check_online, Env};
+use crate::utils::println;
+use anyhow::{anyhow, Context, Result};
+use candid::Principal;
+use candid_parser::utils::CandidSource;
+use console::style;
+use ic_agent::Agent;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Check that the stable variables of the new build can be upgraded from an old .most file or a deployed canister
+pub async fn check_upgrade(agent: &Agent, env: &Env, args: crate::CheckUpgradeArg) -> Result<()> {
+    let (name, new) = get_single_target(env, &args.name, "most")?;
+    let against = &args.against;
+    let old = if Path::new(against).is_file() {
+        PathBuf::from(against)
+    } else if let Ok(id) = Principal::from_text(against) {
+        let most = fetch_metadata(agent, env, id, "motoko:stable-types").await?;
+        let path = new.with_file_name(format!("{id}.most"));
+        fs::write(&path, most)?;
+        path
+    } else {
+        return Err(anyhow!(
+            "{against} is neither a .most file nor a canister id"
+        ));
+    };
+    let mut moc = env.binary["moc"].get_cmd();
+    moc.arg("--stable-compatible").arg(&old).arg(&new);
+    let output = moc
+        .output()
+        .with_context(|| format!("Error executing {:#?}", moc))?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !stderr.trim().is_empty() {
+        println(None, "stderr", stderr.trim_end());
+    }
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Upgrading {against} to {name} is unsafe: the stable variables are not compatible"
+        ));
+    }
+    println(
+        None,
+        "stdout",
+        &format!(
+            "{:>12} stable variables of {name} with {against}",
+            style("Compatible").green().bold()
+        ),
+    );
+    Ok(())
+}
+/// Check that the candid interface of the new build is a subtype of an old interface, so that existing clients keep working
+pub async fn check_candid(agent: &Agent, env: &Env, args: crate::CheckCandidArg) -> Result<()> {
+    let (name, new) = get_single_target(env, &args.name, "did")?;
+    check_candid_file(agent, env, &name, &new, &args.against).await
+}
+pub async fn check_candid_file(
+    agent: &Agent,
+    env: &Env,
+    name: &str,
+    new: &Path,
+    against: &str,
+) -> Result<()> {
+    use candid::types::subtype::subtype;
+    if !new.exists() {
+        return Err(anyhow!(
+            "Cannot find {}. Build the target with --idl.",
+            new.display()
+        ));
+    }
+    let old = if Path::new(against).is_file() {
+        fs::read_to_string(against)?
+    } else if let Ok(id) = Principal::from_text(against) {
+        fetch_metadata(agent, env, id, "candid:service").await?
+    } else {
+        return Err(anyhow!(
+            "{against} is neither a .did file nor a canister id"
+        ));
+    };
+    let (mut type_env, new_actor) = CandidSource::File(new).load()?;
+    let new_actor = new_actor.ok_or_else(|| anyhow!("{} has no service", new.display()))?;
+    let (old_env, old_actor) = CandidSource::Text(&old).load()?;
+    let old_actor = old_actor.ok_or_else(|| anyhow!("{against} has no service"))?;
+    // Display the old types before merging, which may rename them
+    let old_types: BTreeMap<_, _> = old_env
+        .as_service(&old_actor)?
+        .iter()
+        .map(|(method, ty)| (method.clone(), ty.to_string()))
+        .collect();
+    let old_actor = type_env.merge_type(old_env, old_actor);
+    let new_methods: BTreeMap<_, _> = type_env.as_service(&new_actor)?.iter().cloned().collect();
+    let mut breaking = Vec::new();
+    for (method, old_ty) in type_env.as_service(&old_actor)? {
+        match new_methods.get(method) {
+            None => breaking.push(format!("method {method} is removed")),
+            Some(new_ty) => {
+                let mut gamma = HashSet::new();
+                if let Err(e) = subtype(&mut gamma, &type_env, new_ty, old_ty) {
+                    breaking.push(format!(
+                        "method {method} changed from {} to {new_ty}: {e}",
+                        old_types[method]
+                    ));
+                }
+            }
+        }
+    }
+    if !breaking.is_empty() {
+        for change in &breaking {
+            println(
+                None,
+                "stderr",
+                &format!("{:>12} {change}", style("Breaking").red().bold()),
+            );
+        }
+        return Err(anyhow!(
+            "The candid interface of {name} is not compatible with {against}: {} breaking change(s)",
+            breaking.len()
+        ));
+    }
+    println(
+        None,
+        "stdout",
+        &format!(
+            "{:>12} candid interface of {name} with {against}",
+            style("Compatible").green().bold()
+        ),
+    );
+    Ok(())
+}
+/// The built file of the given target, or of the only built target
+fn get_single_target(env: &Env, name: &Option<String>, ext: &str) -> Result<(String, PathBuf)> {
+    let mut files = env.get_built_files(name, ext)?;
+    if files.len() > 1 {
+        let names: Vec<_> = files.into_iter().map(|(name, _)| name).collect();
+        return Err(anyhow!(
+            "Multiple targets are built. Specify one of: {}",
+            names.join(", ")
+        ));
+    }
+    Ok(files.pop().unwrap())
+}
+async fn fetch_metadata(agent: &Agent, env: &Env, id: Principal, name: &str) -> Result<String> {
+    check_online(&format!("fetch {name} of canister {id}"))?;
+    env.network.connect(agent).await?;
+    let bytes = agent
+        .read_state_canister_metadata(id, name)
+        .await
+        .with_context(|| {
+            format!("Failed to fetch the {name} metadata of canister {id}. Private metadata can only be read by the controllers, so build the canister with \"--public-metadata {name}\" if it is private.")
+        })?;
+    println(
+        None,
+        "stdout",
+        &format!(
+            "{:>12} {name} of canister {id} from {}",
+            style("Fetched").green().bold(),
+            env.network.name
+        ),
+    );
+    Ok(String::from_utf8(bytes)?)
+}
@@ -1,6 +1,75 @@
 use anyhow::{anyhow, Context, Result};
+use console::style;
 use indicatif::{ProgressBar, ProgressStyle};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::path::Path;
 use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static JSON_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq, Eq)]
+pub enum MessageFormat {
+    /// Styled text for the terminal
+    Human,
+    /// Newline-delimited JSON events on stdout. Human-readable messages are moved to stderr.
+    Json,
+}
+/// Machine-readable events for `--message-format json`
+#[derive(Serialize)]
+#[serde(tag = "reason", rename_all = "kebab-case")]
+pub enum Event<'a> {
+    PackageResolved {
+        name: &'a str,
+        version: Option<&'a str>,
+        source: &'a str,
+    },
+    PackageDownloaded {
+        name: &'a str,
+        version: &'a str,
+    },
+    CompileStarted {
+        main: &'a Path,
+    },
+    CompileFinished {
+        main: &'a Path,
+        output: Option<&'a Path>,
+        success: bool,
+        duration_ms: u128,
+    },
+    CompilerMessage {
+        diagnostic: &'a crate::diagnostics::Diagnostic,
+    },
+    UpdateAvailable {
+        name: &'a str,
+        current: &'a str,
+        latest: &'a str,
+    },
+    Fresh {
+        main: &'a Path,
+        output: &'a Path,
+    },
+    TestFinished {
+        file: &'a Path,
+        success: bool,
+        output: &'a str,
+    },
+    Error {
+        message: &'a str,
+    },
+}
+pub fn set_message_format(format: MessageFormat) {
+    JSON_OUTPUT.store(format == MessageFormat::Json, Ordering::Relaxed);
+}
+pub fn is_json_output() -> bool {
+    JSON_OUTPUT.load(Ordering::Relaxed)
+}
+pub fn emit(event: Event) {
+    if is_json_output() {
+        println!("{}", serde_json::to_string(&event).unwrap());
+    }
+}
 
 pub fn exec(mut cmd: Command, is_silence: bool, bar: Option<&ProgressBar>) -> Result<String> {
     let output = cmd
@@ -21,7 +90,8 @@ pub fn exec(mut cmd: Command, is_silence: bool, bar: Option<&ProgressBar>) -> Re
 }
 pub fn println(bar: Option<&ProgressBar>, target: &str, msg: &str) {
     if bar.is_none() || bar.is_some_and(|bar| bar.is_hidden()) {
-        if target == "stderr" {
+        // Keep stdout for JSON events
+        if target == "stderr" || is_json_output() {
             eprintln!("{msg}");
         } else {
             println!("{msg}");
@@ -33,6 +103,9 @@ pub fn println(bar: Option<&ProgressBar>, target: &str, msg: &str) {
 }
 
 pub fn create_bar(len: usize) -> ProgressBar {
+    if is_json_output() {
+        return ProgressBar::hidden();
+    }
     ProgressBar::new(len as u64).with_style(
         ProgressStyle::with_template("{prefix:>12.cyan.bold} [{bar:57.green}] {pos}/{len} {msg}")
             .unwrap()
@@ -41,8 +114,114 @@ pub fn create_bar(len: usize) -> ProgressBar {
 }
 
 pub fn create_spinner_bar(msg: impl Into<std::borrow::Cow<'static, str>>) -> ProgressBar {
+    if is_json_output() {
+        return ProgressBar::hidden();
+    }
     let pb = ProgressBar::new_spinner();
     pb.enable_steady_tick(std::time::Duration::from_millis(200));
     pb.set_message(msg);
     pb
 }
+
+/// SHA-256 over all files in the directory, sorted by their relative paths.
+/// When `dir` is the package root in the cache, the DONE and DONE-<base_dir> marker files at the top level are ignored.
+pub fn hash_dir(dir: &Path, skip_markers: bool) -> Result<String> {
+    fn collect(
+        dir: &Path,
+        prefix: &str,
+        skip_markers: bool,
+        files: &mut Vec<(String, std::path::PathBuf)>,
+    ) -> Result<()> {
+        for entry in
+            std::fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))?
+        {
+            let path = entry?.path();
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            let rel = format!("{prefix}{name}");
+            let is_marker = name == "DONE" || name.starts_with("DONE-");
+            if path.is_dir() {
+                collect(&path, &format!("{rel}/"), false, files)?;
+            } else if !(skip_markers && is_marker) {
+                files.push((rel, path));
+            }
+        }
+        Ok(())
+    }
+    let mut files = Vec::new();
+    if dir.is_dir() {
+        collect(dir, "", skip_markers, &mut files)?;
+    }
+    files.sort();
+    let mut hasher = Sha256::new();
+    for (rel, path) in files {
+        let content = std::fs::read(&path)?;
+        hasher.update(rel.as_bytes());
+        hasher.update([0]);
+        hasher.update((content.len() as u64).to_le_bytes());
+        hasher.update(&content);
+    }
+    Ok(to_hex(hasher))
+}
+pub fn to_hex(hasher: Sha256) -> String {
+    let hash: String = hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    format!("sha256:{hash}")
+}
+/// Line diff in the unified format with two lines of context
+pub fn diff_lines(old: &str, new: &str) -> String {
+    let old: Vec<_> = old.lines().collect();
+    let new: Vec<_> = new.lines().collect();
+    // Longest common subsequence from the end
+    let mut lcs = vec![vec![0; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            lines.push((' ', old[i]));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            lines.push(('-', old[i]));
+            i += 1;
+        } else {
+            lines.push(('+', new[j]));
+            j += 1;
+        }
+    }
+    let changed: Vec<_> = lines.iter().map(|(c, _)| *c != ' ').collect();
+    let near_change = |k: usize| {
+        let start = k.saturating_sub(2);
+        let end = (k + 3).min(lines.len());
+        changed[start..end].iter().any(|c| *c)
+    };
+    let mut res = Vec::new();
+    let mut skipped = false;
+    for (k, (c, line)) in lines.iter().enumerate() {
+        if !near_change(k) {
+            skipped = true;
+            continue;
+        }
+        if skipped && !res.is_empty() {
+            res.push(style("...").dim().to_string());
+        }
+        skipped = false;
+        res.push(match c {
+            '+' => style(format!("+ {line}")).green().to_string(),
+            '-' => style(format!("- {line}")).red().to_string(),
+            _ => format!("  {line}"),
+        });
+    }
+    res.join("\n")
+}
@@ -0,0 +1,220 @@
+use crate::env::Env;
+use crate::utils::{exec, println, to_hex};
+use anyhow::{anyhow, Result};
+use console::style;
+use flate2::{write::GzEncoder, Compression};
+use indicatif::{HumanBytes, ProgressBar};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use toml_edit::ImDocument;
+
+const OPTIMIZE_LEVELS: [&str; 7] = ["O0", "O1", "O2", "O3", "O4", "Os", "Oz"];
+
+/// Post-processing of the built Wasm module, configured in the [wasm] section of mops.toml
+pub struct Pipeline {
+    shrink: bool,
+    /// Optimization level passed to `ic-wasm optimize`
+    optimize: Option<String>,
+    gzip: bool,
+    metadata: Vec<Metadata>,
+    /// The [wasm] section as written in mops.toml and the resolved {git_commit} and {timestamp},
+    /// so that changes to either rebuild the targets
+    pub config: String,
+}
+struct Metadata {
+    name: String,
+    /// May contain {candid} and {lock_hash}. {git_commit} and {timestamp} are resolved when reading mops.toml.
+    value: String,
+    public: bool,
+}
+
+impl Pipeline {
+    /// None when there is no [wasm] section in mops.toml
+    pub fn from_mops_toml(env: &Env) -> Result<Option<Self>> {
+        let path = env.get_mops_toml_path();
+        if !path.exists() {
+            return Ok(None);
+        }
+        let str = fs::read_to_string(path)?;
+        let doc = str.parse::<ImDocument<_>>()?;
+        let Some(wasm) = doc.get("wasm") else {
+            return Ok(None);
+        };
+        let wasm = wasm
+            .as_table()
+            .ok_or_else(|| anyhow!("wasm should be a table [wasm]"))?;
+        let get_bool = |field| {
+            wasm.get(field)
+                .map(|v| {
+                    v.as_bool()
+                        .ok_or_else(|| anyhow!("{field} should be a boolean in [wasm]"))
+                })
+                .unwrap_or(Ok(false))
+        };
+        let shrink = get_bool("shrink")?;
+        let gzip = get_bool("gzip")?;
+        let optimize = match wasm.get("optimize") {
+            None => None,
+            Some(level) => {
+                let level = level
+                    .as_str()
+                    .filter(|l| OPTIMIZE_LEVELS.contains(l))
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "optimize should be one of {} in [wasm]",
+                            OPTIMIZE_LEVELS.join(", ")
+                        )
+                    })?;
+                Some(level.to_string())
+            }
+        };
+        let mut metadata = Vec::new();
+        let mut config = wasm.to_string();
+        // The same timestamp is used for all metadata
+        let mut timestamp = None;
+        if let Some(entries) = wasm.get("metadata") {
+            let entries = entries.as_array_of_tables().ok_or_else(|| {
+                anyhow!("metadata should be an array of tables [[wasm.metadata]]")
+            })?;
+            for entry in entries.iter() {
+                let get = |field| entry.get(field).and_then(|v| v.as_str());
+                let name = get("name")
+                    .ok_or_else(|| anyhow!("name is required in [[wasm.metadata]]\n{entry}"))?;
+                let value = match get("value") {
+                    Some(value) => value,
+                    None if name == "candid:service" => "{candid}",
+                    None => return Err(anyhow!("value is required in [[wasm.metadata]]\n{entry}")),
+                };
+                let public = match get("visibility").unwrap_or("private") {
+                    "public" => true,
+                    "private" => false,
+                    _ => {
+                        return Err(anyhow!(
+                            "visibility should be public or private in [[wasm.metadata]]\n{entry}"
+                        ))
+                    }
+                };
+                let resolved = resolve_build_info(env, value, &mut timestamp)?;
+                if resolved != value {
+                    config.push_str(&format!("\n# {name} = {resolved}"));
+                }
+                metadata.push(Metadata {
+                    name: name.to_string(),
+                    value: resolved,
+                    public,
+                });
+            }
+        }
+        Ok(Some(Pipeline {
+            shrink,
+            optimize,
+            gzip,
+            metadata,
+            config,
+        }))
+    }
+    pub fn needs_ic_wasm(&self) -> bool {
+        self.shrink || self.optimize.is_some() || !self.metadata.is_empty()
+    }
+    /// Process the Wasm module in place, and write <name>.wasm.gz when gzip is enabled
+    pub fn run(&self, env: &Env, wasm: &Path, bar: &ProgressBar) -> Result<()> {
+        let original = fs::metadata(wasm)?.len();
+        if self.shrink {
+            self.ic_wasm(env, wasm, &["shrink"])?;
+        }
+        if let Some(level) = &self.optimize {
+            self.ic_wasm(env, wasm, &["optimize", level])?;
+        }
+        for m in &self.metadata {
+            let value = self.get_value(env, wasm, &m.value)?;
+            // Metadata can be large, e.g., candid:service, so it is passed as a file
+            let file = wasm.with_extension("metadata");
+            fs::write(&file, value)?;
+            let file_arg = file.to_string_lossy().to_string();
+            let visibility = if m.public { "public" } else { "private" };
+            let res = self.ic_wasm(
+                env,
+                wasm,
+                &["metadata", &m.name, "-f", &file_arg, "-v", visibility],
+            );
+            fs::remove_file(&file)?;
+            res?;
+        }
+        let size = fs::metadata(wasm)?.len();
+        let mut msg = format!(
+            "{:>12} {} ({} -> {}",
+            style("Processed").green().bold(),
+            wasm.strip_prefix(&env.project_root)
+                .unwrap_or(wasm)
+                .display(),
+            HumanBytes(original),
+            HumanBytes(size)
+        );
+        if self.gzip {
+            let gz = wasm.with_extension("wasm.gz");
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+            encoder.write_all(&fs::read(wasm)?)?;
+            let bytes = encoder.finish()?;
+            fs::write(&gz, &bytes)?;
+            msg.push_str(&format!(", {} gzipped", HumanBytes(bytes.len() as u64)));
+        }
+        msg.push(')');
+        println(Some(bar), "stdout", &msg);
+        Ok(())
+    }
+    fn ic_wasm(&self, env: &Env, wasm: &Path, args: &[&str]) -> Result<()> {
+        let mut cmd = env.binary["ic-wasm"].get_cmd();
+        cmd.arg(wasm).arg("-o").arg(wasm).args(args);
+        exec(cmd, true, None).map_err(|e| anyhow!("ic-wasm {} failed: {e:#}", args[0]))?;
+        Ok(())
+    }
+    fn get_value(&self, env: &Env, wasm: &Path, value: &str) -> Result<String> {
+        let mut res = value.to_string();
+        if res.contains("{candid}") {
+            let did = wasm.with_extension("did");
+            let candid = fs::read_to_string(&did).map_err(|_| {
+                anyhow!(
+                    "Cannot find {} for metadata. Build the target with --idl.",
+                    did.display()
+                )
+            })?;
+            res = res.replace("{candid}", &candid);
+        }
+        if res.contains("{lock_hash}") {
+            let mut hasher = Sha256::new();
+            hasher.update(fs::read(env.get_mops_lock_path()).unwrap_or_default());
+            res = res.replace("{lock_hash}", &to_hex(hasher));
+        }
+        Ok(res)
+    }
+}
+/// Replace {git_commit} and {timestamp}, which do not depend on the build output.
+/// They are resolved before the freshness check, so that a new commit or timestamp rebuilds the targets.
+fn resolve_build_info(env: &Env, value: &str, timestamp: &mut Option<u64>) -> Result<String> {
+    let mut res = value.to_string();
+    if res.contains("{git_commit}") {
+        let mut git = std::process::Command::new("git");
+        git.current_dir(&env.project_root)
+            .args(["rev-parse", "HEAD"]);
+        let commit = exec(git, true, None)
+            .map_err(|_| anyhow!("Cannot read the git commit for metadata"))?;
+        res = res.replace("{git_commit}", commit.trim());
+    }
+    if res.contains("{timestamp}") {
+        use std::time::SystemTime;
+        let now = match timestamp {
+            Some(now) => *now,
+            None => {
+                let now = SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)?
+                    .as_secs();
+                *timestamp = Some(now);
+                now
+            }
+        };
+        res = res.replace("{timestamp}", &now.to_string());
+    }
+    Ok(res)
+}